@@ -1 +1,298 @@
-pub const MIN_SECONDS_BETWEEN_UPDATES: i64 = 40;
+use std::time::Duration;
+
+use libquavertrack::{
+    api::{
+        fixtures::FixtureMode, rate_limit::RateLimitConfig, QuaverClientConfig, DEFAULT_BASE_URL,
+    },
+    retention::RetentionPolicy,
+    scheduling::RefreshPolicy,
+};
+use rocket::figment::Figment;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::scheduler::SchedulerConfig;
+
+pub const DEFAULT_ACTIVITY_PAGE_SIZE: i64 = 50;
+pub const MAX_ACTIVITY_PAGE_SIZE: i64 = 200;
+/// Most users to suggest when a lookup doesn't match exactly one user
+pub const MAX_USER_CANDIDATES: i64 = 10;
+pub const DEFAULT_USER_SEARCH_RESULTS: i64 = 10;
+pub const MAX_USER_SEARCH_RESULTS: i64 = 50;
+pub const DEFAULT_STATS_HISTORY_PAGE_SIZE: i64 = 1000;
+pub const MAX_STATS_HISTORY_PAGE_SIZE: i64 = 5000;
+
+/// Runtime configuration, extracted from the same sources as Rocket's own config: `Rocket.toml`
+/// and `ROCKET_`-prefixed environment variables.  For example, `ROCKET_UPDATE_TOKENS=[abc]` or
+/// `ROCKET_SCHEDULER={concurrency=4}`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Tokens accepted by admin routes such as `update_oldest`.  If empty, those routes reject
+    /// every request.
+    pub update_tokens: Vec<String>,
+    /// Minimum time between updates of the same user triggered by the same client
+    pub min_seconds_between_updates: i64,
+    /// Minimum time between updates of the same user no matter who triggers them, measured from
+    /// the user's last stored update
+    pub min_seconds_between_user_updates: i64,
+    /// Header that a trusted reverse proxy puts the client's IP in, e.g. `X-Real-IP`.  Only set
+    /// this when the app is only reachable through that proxy, since clients can set any header
+    /// themselves.  If unset, the IP of the connecting peer is used.
+    pub trusted_proxy_header: Option<String>,
+    pub quaver_api_base_url: String,
+    /// Record Quaver API responses as fixtures or replay previously recorded ones, e.g.
+    /// `fixtures = { mode = "record", dir = "../libquavertrack/fixtures" }`
+    pub fixtures: FixtureMode,
+    pub rate_limit: RateLimitConf,
+    pub scheduler: SchedulerConf,
+    pub retention: RetentionConf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            update_tokens: Vec::new(),
+            min_seconds_between_updates: 40,
+            min_seconds_between_user_updates: 10,
+            trusted_proxy_header: None,
+            quaver_api_base_url: DEFAULT_BASE_URL.to_owned(),
+            fixtures: FixtureMode::default(),
+            rate_limit: RateLimitConf::default(),
+            scheduler: SchedulerConf::default(),
+            retention: RetentionConf::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConf {
+    pub requests_per_second: f64,
+    pub burst: u32,
+    pub rate_limited_backoff_secs: u64,
+}
+
+impl Default for RateLimitConf {
+    fn default() -> Self {
+        let defaults = RateLimitConfig::default();
+        RateLimitConf {
+            requests_per_second: defaults.requests_per_second,
+            burst: defaults.burst,
+            rate_limited_backoff_secs: defaults.rate_limited_backoff.as_secs(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SchedulerConf {
+    pub enabled: bool,
+    pub concurrency: usize,
+    pub cycle_budget: usize,
+    pub cycle_interval_secs: u64,
+    pub min_interval_secs: u64,
+    pub max_interval_secs: u64,
+}
+
+impl Default for SchedulerConf {
+    fn default() -> Self {
+        let defaults = SchedulerConfig::default();
+        SchedulerConf {
+            enabled: defaults.enabled,
+            concurrency: defaults.concurrency,
+            cycle_budget: defaults.cycle_budget,
+            cycle_interval_secs: defaults.cycle_interval.as_secs(),
+            min_interval_secs: defaults.refresh_policy.min_interval.as_secs(),
+            max_interval_secs: defaults.refresh_policy.max_interval.as_secs(),
+        }
+    }
+}
+
+/// Settings for the job that rolls up old stats snapshots; see `RetentionPolicy`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct RetentionConf {
+    pub enabled: bool,
+    /// How long to wait between runs
+    pub interval_secs: u64,
+    pub raw_retention_days: u64,
+    pub daily_retention_days: u64,
+}
+
+impl Default for RetentionConf {
+    fn default() -> Self {
+        let defaults = RetentionPolicy::default();
+        RetentionConf {
+            enabled: true,
+            interval_secs: 6 * 60 * 60,
+            raw_retention_days: defaults.raw_retention.as_secs() / SECONDS_PER_DAY,
+            daily_retention_days: defaults.daily_retention.as_secs() / SECONDS_PER_DAY,
+        }
+    }
+}
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error(
+        "background jobs hold {required} database connections but the pool only has {pool_size}; \
+         at least one must be left for requests"
+    )]
+    PoolTooSmall { required: usize, pool_size: u32 },
+    #[error("rate_limit.requests_per_second must be positive and finite, got {0}")]
+    InvalidRequestsPerSecond(f64),
+    #[error("rate_limit.burst must be at least 1")]
+    ZeroBurst,
+    #[error("retention.interval_secs must be greater than 0")]
+    ZeroRetentionInterval,
+}
+
+/// Returns the size of the `quavertrack` database pool, defaulting the same way as
+/// `rocket_sync_db_pools` does when it isn't set explicitly
+pub fn db_pool_size(figment: &Figment) -> Option<u32> {
+    figment
+        .extract_inner("databases.quavertrack.pool_size")
+        .ok()
+        .or_else(|| {
+            figment
+                .extract_inner::<u32>(rocket::Config::WORKERS)
+                .ok()
+                .map(|workers| workers * 4)
+        })
+}
+
+impl Config {
+    /// Checks settings that can't be expressed through types alone.  `db_pool_size` is the number
+    /// of connections in the database pool, which the background jobs take some of for as long as
+    /// the app runs.
+    pub fn validate(&self, db_pool_size: u32) -> Result<(), ConfigError> {
+        let requests_per_second = self.rate_limit.requests_per_second;
+        if !(requests_per_second.is_finite() && requests_per_second > 0.) {
+            return Err(ConfigError::InvalidRequestsPerSecond(requests_per_second));
+        }
+        if self.rate_limit.burst == 0 {
+            return Err(ConfigError::ZeroBurst);
+        }
+        if self.retention.interval_secs == 0 {
+            return Err(ConfigError::ZeroRetentionInterval);
+        }
+
+        let required = self.background_connections();
+        if required >= db_pool_size as usize {
+            return Err(ConfigError::PoolTooSmall {
+                required,
+                pool_size: db_pool_size,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Number of database connections held permanently by the scheduler and retention job
+    fn background_connections(&self) -> usize {
+        let scheduler = if self.scheduler.enabled {
+            self.scheduler.concurrency.max(1)
+        } else {
+            0
+        };
+        let retention = if self.retention.enabled { 1 } else { 0 };
+
+        scheduler + retention
+    }
+
+    pub fn is_valid_update_token(&self, token: &str) -> bool {
+        self.update_tokens
+            .iter()
+            .any(|valid_token| valid_token == token)
+    }
+
+    pub fn client_config(&self) -> QuaverClientConfig {
+        QuaverClientConfig {
+            base_url: self.quaver_api_base_url.clone(),
+            fixtures: self.fixtures.clone(),
+            rate_limit: RateLimitConfig {
+                requests_per_second: self.rate_limit.requests_per_second,
+                burst: self.rate_limit.burst,
+                rate_limited_backoff: Duration::from_secs(
+                    self.rate_limit.rate_limited_backoff_secs,
+                ),
+            },
+            ..QuaverClientConfig::default()
+        }
+    }
+
+    pub fn scheduler_config(&self) -> SchedulerConfig {
+        SchedulerConfig {
+            enabled: self.scheduler.enabled,
+            concurrency: self.scheduler.concurrency,
+            cycle_budget: self.scheduler.cycle_budget,
+            cycle_interval: Duration::from_secs(self.scheduler.cycle_interval_secs),
+            refresh_policy: RefreshPolicy {
+                min_interval: Duration::from_secs(self.scheduler.min_interval_secs),
+                max_interval: Duration::from_secs(self.scheduler.max_interval_secs),
+                ..RefreshPolicy::default()
+            },
+        }
+    }
+
+    pub fn retention_policy(&self) -> RetentionPolicy {
+        RetentionPolicy {
+            raw_retention: Duration::from_secs(self.retention.raw_retention_days * SECONDS_PER_DAY),
+            daily_retention: Duration::from_secs(
+                self.retention.daily_retention_days * SECONDS_PER_DAY,
+            ),
+        }
+    }
+}
+
+#[test]
+fn background_jobs_must_leave_connections_for_requests() {
+    let mut config = Config::default();
+    config.scheduler.concurrency = 3;
+    assert!(config.validate(5).is_ok());
+    assert!(matches!(
+        config.validate(4),
+        Err(ConfigError::PoolTooSmall {
+            required: 4,
+            pool_size: 4
+        })
+    ));
+
+    config.retention.enabled = false;
+    assert!(config.validate(4).is_ok());
+    config.scheduler.enabled = false;
+    assert!(config.validate(1).is_ok());
+}
+
+#[test]
+fn requests_per_second_must_be_positive() {
+    let mut config = Config::default();
+    assert!(config.validate(10).is_ok());
+
+    for &requests_per_second in &[0., -1., f64::NAN, f64::INFINITY] {
+        config.rate_limit.requests_per_second = requests_per_second;
+        assert!(matches!(
+            config.validate(10),
+            Err(ConfigError::InvalidRequestsPerSecond(_))
+        ));
+    }
+}
+
+#[test]
+fn burst_must_be_nonzero() {
+    let mut config = Config::default();
+    config.rate_limit.burst = 0;
+    assert!(matches!(config.validate(10), Err(ConfigError::ZeroBurst)));
+}
+
+#[test]
+fn retention_interval_must_be_nonzero() {
+    let mut config = Config::default();
+    config.retention.interval_secs = 0;
+    assert!(matches!(
+        config.validate(10),
+        Err(ConfigError::ZeroRetentionInterval)
+    ));
+}
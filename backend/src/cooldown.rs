@@ -0,0 +1,240 @@
+use std::{net::IpAddr, sync::Mutex};
+
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use fnv::FnvHashMap as HashMap;
+use rocket::{
+    request::{FromRequest, Outcome},
+    Request,
+};
+use serde::Serialize;
+
+use crate::conf::Config;
+
+/// A user ID and the IP of the client that triggered the update, if known
+type CooldownKey = (i64, Option<IpAddr>);
+
+/// Limits how often each client can trigger updates of each user.  Cooldowns are tracked per
+/// `(user, client)` pair so that one client spamming updates for a user doesn't stop anyone else
+/// from updating them.  On top of that, nobody can update a user again within `user_floor` of
+/// that user's last stored update, however many clients are asking.
+pub struct UpdateCooldowns {
+    cooldown: Duration,
+    user_floor: Duration,
+    state: Mutex<CooldownState>,
+}
+
+#[derive(Default)]
+struct CooldownState {
+    /// When each client last updated each user, or started an update that's still running
+    last_updates: HashMap<CooldownKey, DateTime<Utc>>,
+    /// When each user's last update through here was started, including ones that are still
+    /// running.  Updates that haven't been stored yet don't show up in the user's
+    /// `last_updated_at`, so these are what stops concurrent updates from different clients.
+    user_updates: HashMap<i64, DateTime<Utc>>,
+}
+
+/// Returned when a client tries to update a user again before its cooldown is up
+#[derive(Debug, Serialize)]
+pub struct UpdateCooldown {
+    pub seconds_remaining: i64,
+    /// When this client, or anyone for the per-user floor, last updated this user
+    pub last_updated_at: NaiveDateTime,
+}
+
+/// A client's claim on updating a user, returned by [`UpdateCooldowns::reserve`].  Dropping it
+/// without calling [`complete`](Self::complete), e.g. because the update failed, gives the slot
+/// back as if the update had never been attempted.
+#[must_use]
+pub struct CooldownReservation<'a> {
+    cooldowns: &'a UpdateCooldowns,
+    key: CooldownKey,
+    started_at: DateTime<Utc>,
+    /// The client's and the user's last updates before this one, restored if the update doesn't
+    /// go through
+    previous_client_update: Option<DateTime<Utc>>,
+    previous_user_update: Option<DateTime<Utc>>,
+    completed: bool,
+}
+
+impl UpdateCooldowns {
+    pub fn new(cooldown: Duration, user_floor: Duration) -> Self {
+        UpdateCooldowns {
+            cooldown,
+            user_floor,
+            state: Mutex::new(CooldownState::default()),
+        }
+    }
+
+    /// Reserves an update of `user_id` by `client` at `now` if it's allowed.  `user_last_updated_at`
+    /// is when the user was last updated by anyone, as stored in the database.  Checking and
+    /// reserving happen together so that concurrent requests can't all get through before the
+    /// first one finishes.  If the update isn't allowed yet, returns how long the client has to
+    /// wait.
+    pub fn reserve(
+        &self,
+        user_id: i64,
+        client: Option<IpAddr>,
+        user_last_updated_at: Option<NaiveDateTime>,
+        now: DateTime<Utc>,
+    ) -> Result<CooldownReservation<'_>, UpdateCooldown> {
+        let key = (user_id, client);
+        let mut state = self.state.lock().unwrap();
+
+        let client_last_update = state
+            .last_updates
+            .get(&key)
+            .map(|last_update| (last_update.naive_utc(), self.cooldown));
+        let user_last_update = state
+            .user_updates
+            .get(&user_id)
+            .map(DateTime::naive_utc)
+            .max(user_last_updated_at)
+            .map(|last_updated_at| (last_updated_at, self.user_floor));
+
+        let longest_wait = client_last_update
+            .into_iter()
+            .chain(user_last_update)
+            .map(|(last_update, cooldown)| (last_update, last_update + cooldown - now.naive_utc()))
+            .max_by_key(|&(_, remaining)| remaining);
+        if let Some((last_updated_at, remaining)) = longest_wait {
+            if remaining > Duration::zero() {
+                return Err(UpdateCooldown {
+                    // Round up so that clients that wait exactly this long are allowed through
+                    seconds_remaining: (remaining.num_milliseconds() + 999) / 1000,
+                    last_updated_at,
+                });
+            }
+        }
+
+        // Forget about updates whose cooldowns are over so that this doesn't grow forever
+        let (cooldown, user_floor) = (self.cooldown, self.user_floor);
+        state
+            .last_updates
+            .retain(|_, last_update| *last_update + cooldown > now);
+        state
+            .user_updates
+            .retain(|_, last_update| *last_update + user_floor > now);
+
+        let previous_client_update = state.last_updates.insert(key, now);
+        let previous_user_update = state.user_updates.insert(user_id, now);
+        Ok(CooldownReservation {
+            cooldowns: self,
+            key,
+            started_at: now,
+            previous_client_update,
+            previous_user_update,
+            completed: false,
+        })
+    }
+}
+
+impl CooldownReservation<'_> {
+    /// Records that the update was stored at `now`, starting the client's cooldown
+    pub fn complete(mut self, now: DateTime<Utc>) {
+        self.cooldowns
+            .state
+            .lock()
+            .unwrap()
+            .last_updates
+            .insert(self.key, now);
+        self.completed = true;
+    }
+}
+
+impl Drop for CooldownReservation<'_> {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+
+        let mut state = self.cooldowns.state.lock().unwrap();
+        let (user_id, _) = self.key;
+        restore(
+            &mut state.last_updates,
+            self.key,
+            self.started_at,
+            self.previous_client_update,
+        );
+        restore(
+            &mut state.user_updates,
+            user_id,
+            self.started_at,
+            self.previous_user_update,
+        );
+    }
+}
+
+/// Puts back the update time stored under `key` before the one started at `started_at`, unless
+/// another update has replaced it since
+fn restore<K: Eq + std::hash::Hash>(
+    updates: &mut HashMap<K, DateTime<Utc>>,
+    key: K,
+    started_at: DateTime<Utc>,
+    previous: Option<DateTime<Utc>>,
+) {
+    if updates.get(&key) != Some(&started_at) {
+        return;
+    }
+    match previous {
+        Some(previous) => updates.insert(key, previous),
+        None => updates.remove(&key),
+    };
+}
+
+/// The IP of the client making a request.  This is the address of the connecting peer unless
+/// `trusted_proxy_header` is configured, in which case it's taken from that header as set by the
+/// proxy.  Headers like `X-Forwarded-For` can hold a list of addresses, in which case the last
+/// one, added by the trusted proxy itself, is used.
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let remote_ip = req.remote().map(|remote| remote.ip());
+        let header = req
+            .rocket()
+            .state::<Config>()
+            .and_then(|config| config.trusted_proxy_header.as_deref());
+
+        let ip = match header {
+            Some(header) => req
+                .headers()
+                .get(header)
+                .last()
+                .and_then(|value| value.rsplit(',').next())
+                .and_then(|ip| ip.trim().parse().ok())
+                .or(remote_ip),
+            None => remote_ip,
+        };
+        Outcome::Success(ClientIp(ip))
+    }
+}
+
+#[test]
+fn concurrent_updates_are_limited() {
+    let cooldowns = UpdateCooldowns::new(Duration::seconds(30), Duration::seconds(10));
+    let client = Some("10.0.0.1".parse().unwrap());
+    let other_client = Some("10.0.0.2".parse().unwrap());
+    let now = Utc::now();
+
+    let reservation = cooldowns.reserve(1, client, None, now).unwrap();
+    // Neither the same client nor anyone else can update the user while the update is running
+    assert!(cooldowns.reserve(1, client, None, now).is_err());
+    assert!(cooldowns.reserve(1, other_client, None, now).is_err());
+    assert!(cooldowns.reserve(2, client, None, now).is_ok());
+
+    // Failed updates give the slot back
+    drop(reservation);
+    let reservation = cooldowns.reserve(1, client, None, now).unwrap();
+    reservation.complete(now);
+    let err = cooldowns
+        .reserve(1, client, None, now + Duration::seconds(15))
+        .err()
+        .unwrap();
+    assert_eq!(err.seconds_remaining, 15);
+    assert!(cooldowns
+        .reserve(1, other_client, None, now + Duration::seconds(15))
+        .is_ok());
+}
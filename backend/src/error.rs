@@ -0,0 +1,143 @@
+use libquavertrack::{
+    api::APIError,
+    db_util::models::{
+        InvalidCursorError, InvalidModeError, InvalidResolutionError, UserCandidate,
+    },
+};
+use rocket::{
+    http::Status,
+    response::{self, Responder},
+    serde::json::Json,
+    Request, Response,
+};
+use serde_json::json;
+use thiserror::Error;
+
+use crate::{cooldown::UpdateCooldown, UpdateUserError};
+
+/// Error returned by all API routes.  Responds with a JSON body like
+/// `{"error": "user_not_found", "message": "User not found"}` so that clients can branch on the
+/// error code.  Ambiguous lookups respond with 300 and list the users that could have been meant
+/// under `candidates`.
+#[derive(Debug, Error)]
+pub enum RouteError {
+    #[error("User not found")]
+    UserNotFound,
+    #[error("Map not found")]
+    MapNotFound,
+    #[error("Multiple users match; choose one of the candidates")]
+    AmbiguousUser(Vec<UserCandidate>),
+    /// Upstream couldn't be reached or returned an error.  Details are only logged.
+    #[error("Error getting data from Quaver API")]
+    UpstreamUnavailable(APIError),
+    /// Upstream returned a response that we couldn't decode, which is a bug on our end
+    #[error("Error reading data from Quaver API")]
+    BadUpstreamResponse(APIError),
+    #[error("Updated too recently; must wait {} more seconds", .0.seconds_remaining)]
+    RateLimited(UpdateCooldown),
+    #[error("{0}")]
+    InvalidMode(#[from] InvalidModeError),
+    #[error("{0}")]
+    InvalidResolution(#[from] InvalidResolutionError),
+    #[error("{0}")]
+    InvalidCursor(#[from] InvalidCursorError),
+    #[error("`rating` must be a non-negative number")]
+    InvalidRating,
+    #[error("Invalid timestamp for `{0}`; expected milliseconds since the Unix epoch")]
+    InvalidTimestamp(&'static str),
+    #[error("Error querying database")]
+    DBError(#[from] diesel::result::Error),
+    #[error("Invalid token provided")]
+    Unauthorized,
+}
+
+impl RouteError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            RouteError::UserNotFound => "user_not_found",
+            RouteError::MapNotFound => "map_not_found",
+            RouteError::AmbiguousUser(_) => "ambiguous_user",
+            RouteError::UpstreamUnavailable(_) => "upstream_unavailable",
+            RouteError::BadUpstreamResponse(_) => "bad_upstream_response",
+            RouteError::RateLimited(_) => "rate_limited",
+            RouteError::InvalidMode(_) => "invalid_mode",
+            RouteError::InvalidResolution(_) => "invalid_resolution",
+            RouteError::InvalidCursor(_) => "invalid_cursor",
+            RouteError::InvalidRating => "invalid_rating",
+            RouteError::InvalidTimestamp(_) => "invalid_timestamp",
+            RouteError::DBError(_) => "db_error",
+            RouteError::Unauthorized => "unauthorized",
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match self {
+            RouteError::UserNotFound | RouteError::MapNotFound => Status::NotFound,
+            RouteError::AmbiguousUser(_) => Status::MultipleChoices,
+            RouteError::UpstreamUnavailable(_) => Status::BadGateway,
+            RouteError::RateLimited(_) => Status::TooManyRequests,
+            RouteError::InvalidMode(_)
+            | RouteError::InvalidResolution(_)
+            | RouteError::InvalidCursor(_)
+            | RouteError::InvalidRating
+            | RouteError::InvalidTimestamp(_) => Status::BadRequest,
+            RouteError::BadUpstreamResponse(_) | RouteError::DBError(_) => {
+                Status::InternalServerError
+            }
+            RouteError::Unauthorized => Status::Unauthorized,
+        }
+    }
+}
+
+impl From<UpdateUserError> for RouteError {
+    fn from(err: UpdateUserError) -> Self {
+        match err {
+            UpdateUserError::APIError(err) => err.into(),
+            UpdateUserError::NotFound => RouteError::UserNotFound,
+            UpdateUserError::DBError(err) => RouteError::DBError(err),
+        }
+    }
+}
+
+impl From<APIError> for RouteError {
+    fn from(err: APIError) -> Self {
+        match err {
+            APIError::Decode { .. } => RouteError::BadUpstreamResponse(err),
+            _ => RouteError::UpstreamUnavailable(err),
+        }
+    }
+}
+
+impl From<UpdateCooldown> for RouteError {
+    fn from(cooldown: UpdateCooldown) -> Self {
+        RouteError::RateLimited(cooldown)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for RouteError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        match &self {
+            RouteError::UpstreamUnavailable(_)
+            | RouteError::BadUpstreamResponse(_)
+            | RouteError::DBError(_) => error!("Error handling {}: {:?}", req.uri(), self),
+            _ => warn!("Error handling {}: {}", req.uri(), self),
+        }
+
+        let mut body = json!({ "error": self.code(), "message": self.to_string() });
+        let mut response = Response::build();
+        match &self {
+            RouteError::RateLimited(cooldown) => {
+                body["seconds_remaining"] = json!(cooldown.seconds_remaining);
+                body["last_updated_at"] = json!(cooldown.last_updated_at);
+                response.raw_header("Retry-After", cooldown.seconds_remaining.to_string());
+            }
+            RouteError::AmbiguousUser(candidates) => body["candidates"] = json!(candidates),
+            _ => (),
+        }
+
+        response
+            .merge(Json(body).respond_to(req)?)
+            .status(self.status())
+            .ok()
+    }
+}
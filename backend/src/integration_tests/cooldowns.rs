@@ -0,0 +1,78 @@
+use rocket::http::{Header, Status};
+
+use super::{read_json, TestApp};
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn update_cooldown_is_per_client() {
+    let app =
+        TestApp::start_with(|figment| figment.merge(("min_seconds_between_user_updates", 1))).await;
+
+    let (status, _) = app.update("ameo", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::Ok);
+
+    let res = app
+        .client
+        .post("/api/update/ameo")
+        .remote("10.0.0.1:1234".parse().unwrap())
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::TooManyRequests);
+    let retry_after: i64 = res
+        .headers()
+        .get_one("Retry-After")
+        .unwrap()
+        .parse()
+        .unwrap();
+    let (_, body) = read_json(res).await;
+    assert_eq!(body["error"], "rate_limited");
+    assert_eq!(body["seconds_remaining"], retry_after);
+    assert!(retry_after > 10 && retry_after <= 40);
+
+    // Nobody can update the user again right away...
+    let (status, body) = app.update("ameo", "10.0.0.2:1234").await;
+    assert_eq!(status, Status::TooManyRequests);
+    assert_eq!(body["seconds_remaining"], 1);
+
+    // ...but once the per-user floor is over, other clients aren't affected
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    let (status, _) = app.update("ameo", "10.0.0.2:1234").await;
+    assert_eq!(status, Status::Ok);
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+
+    // Clients can't pick their own IP unless a trusted proxy header is configured
+    let res = app
+        .client
+        .post("/api/update/ameo")
+        .remote("10.0.0.1:1234".parse().unwrap())
+        .header(Header::new("X-Real-IP", "10.0.0.3"))
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::TooManyRequests);
+}
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn update_cooldown_behind_proxy() {
+    let app =
+        TestApp::start_with(|figment| figment.merge(("trusted_proxy_header", "X-Forwarded-For")))
+            .await;
+    let app = &app;
+    let update_from = |forwarded_for: &'static str| async move {
+        app.client
+            .post("/api/update/ameo")
+            .remote("127.0.0.1:1234".parse().unwrap())
+            .header(Header::new("X-Forwarded-For", forwarded_for))
+            .dispatch()
+            .await
+            .status()
+    };
+
+    assert_eq!(update_from("10.0.0.1").await, Status::Ok);
+    // The address added by the proxy is used rather than ones sent by the client
+    assert_eq!(
+        update_from("10.0.0.2, 10.0.0.1").await,
+        Status::TooManyRequests
+    );
+    assert_eq!(update_from("10.0.0.1, 10.0.0.2").await, Status::Ok);
+}
@@ -0,0 +1,164 @@
+use libquavertrack::db_util::{self, models::APIUser};
+use rocket::http::Status;
+
+use super::{TestApp, FIXTURES_DIR, FIXTURE_USER_ID};
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn users_are_found_by_previous_and_similar_usernames() {
+    let app = TestApp::start().await;
+
+    let (status, _) = app.update("ameo", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::Ok);
+    let renamed = std::fs::read_to_string(format!("{}/v1/users/full/19250.json", FIXTURES_DIR))
+        .unwrap()
+        .replace(r#""username": "ameo""#, r#""username": "Quaver_Ameo""#);
+    app.mock.enqueue("/v1/users/full/19250/", 200, &renamed);
+    let (status, _) = app.update("ameo", "10.0.0.2:1234").await;
+    assert_eq!(status, Status::Ok);
+
+    // Old usernames still work, ignoring case, as long as nobody upstream has taken them since
+    app.mock
+        .enqueue("/v1/users/search/AMEO", 200, r#"{"status":200,"users":[]}"#);
+    for user in &["AMEO", "quaver_ameo"] {
+        let (status, body) = app
+            .get(&format!("/api/user/{}/4k/stats_history", user))
+            .await;
+        assert_eq!(status, Status::Ok, "{}: {}", user, body);
+        assert_eq!(body["updates"].as_array().unwrap().len(), 1, "{}", user);
+    }
+
+    // Once someone else has the name, it refers to them instead
+    app.mock.enqueue(
+        "/v1/users/search/ameo",
+        200,
+        r#"{"status":200,"users":[{"id":555,"username":"ameo"}]}"#,
+    );
+    let new_owner = std::fs::read_to_string(format!("{}/v1/users/id=19250.json", FIXTURES_DIR))
+        .unwrap()
+        .replace("19250", "555");
+    app.mock.enqueue("/v1/users?id=555", 200, new_owner);
+    let (status, body) = app.get("/api/user/ameo/4k/stats_history").await;
+    assert_eq!(status, Status::Ok, "{}", body);
+    assert!(body["updates"].as_array().unwrap().is_empty());
+
+    // Names that don't match anyone exactly suggest similar tracked users
+    app.mock.enqueue(
+        "/v1/users/search/quaver_amoe",
+        200,
+        r#"{"status":200,"users":[]}"#,
+    );
+    let (status, body) = app.get("/api/user/quaver_amoe/4k/scores").await;
+    assert_eq!(status, Status::MultipleChoices);
+    assert_eq!(body["error"], "ambiguous_user");
+    assert_eq!(body["candidates"][0]["id"], FIXTURE_USER_ID);
+    assert_eq!(body["candidates"][0]["username"], "quaver_ameo");
+
+    // Updates only ever act on an exact match
+    app.mock.enqueue(
+        "/v1/users/search/quaver_amoe",
+        200,
+        r#"{"status":200,"users":[]}"#,
+    );
+    let (status, body) = app.update("quaver_amoe", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::NotFound);
+    assert_eq!(body["error"], "user_not_found");
+
+    // As do upstream searches that return several users, none with exactly that name
+    app.mock.enqueue(
+        "/v1/users/search/someone",
+        200,
+        r#"{"status":200,"users":[{"id":1,"username":"someone1"},{"id":2,"username":"Someone2"}]}"#,
+    );
+    let (status, body) = app.get("/api/user/someone/4k/scores").await;
+    assert_eq!(status, Status::MultipleChoices);
+    let candidates: Vec<&str> = body["candidates"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|candidate| candidate["username"].as_str().unwrap())
+        .collect();
+    assert_eq!(candidates, vec!["someone1", "someone2"]);
+
+    // Even a single search result is only a suggestion unless its name matches exactly
+    for _ in 0..2 {
+        app.mock.enqueue(
+            "/v1/users/search/someone",
+            200,
+            r#"{"status":200,"users":[{"id":1,"username":"someone1"}]}"#,
+        );
+    }
+    let (status, body) = app.get("/api/user/someone/4k/scores").await;
+    assert_eq!(status, Status::MultipleChoices);
+    assert_eq!(body["candidates"][0]["id"], 1);
+    let (status, _) = app.update("someone", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::NotFound);
+    assert!(!app.mock.requests().contains(&"/v1/users?id=1".to_owned()));
+
+    app.mock.enqueue(
+        "/v1/users/search/nobody_like_this",
+        200,
+        r#"{"status":200,"users":[]}"#,
+    );
+    let (status, body) = app.get("/api/user/nobody_like_this/4k/scores").await;
+    assert_eq!(status, Status::NotFound, "{}", body);
+}
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn tracked_user_search() {
+    let app = TestApp::start().await;
+
+    let (status, _) = app.update("ameo", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::Ok);
+    // Users that are tracked but haven't been updated
+    let conn = app.db.conn();
+    for (id, username) in &[(1, "ameoba"), (2, "ameo_fan"), (3, "someone_else")] {
+        let user: APIUser = serde_json::from_value(serde_json::json!({
+            "id": id,
+            "username": username,
+            "country": "CA",
+            "avatar_url": "https://example.com/avatar.png",
+        }))
+        .unwrap();
+        db_util::store_user(&conn, user.into(), chrono::Utc::now().naive_utc()).unwrap();
+    }
+
+    let app = &app;
+    let search = |query: &'static str| async move {
+        let (status, results) = app.get(&format!("/api/users/search?q={}", query)).await;
+        assert_eq!(status, Status::Ok);
+        results
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| result["username"].as_str().unwrap().to_owned())
+            .collect::<Vec<_>>()
+    };
+    let requests_before = app.mock.requests().len();
+
+    // The active user comes first among prefix matches
+    assert_eq!(search("ame").await, vec!["ameo", "ameoba", "ameo_fan"]);
+    // Exact matches come first, ignoring case and accents, and `_` isn't a wildcard
+    assert_eq!(search("Am%C3%A9o").await[0], "ameo");
+    assert_eq!(search("AMEO_F").await[0], "ameo_fan");
+    let (_, results) = app.get("/api/users/search?q=ame_").await;
+    assert!(results
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|result| result["rank"].as_f64().unwrap() < 2.));
+    // Similar names match too
+    assert_eq!(search("someone_els").await[0], "someone_else");
+    assert!(search("zzzz").await.is_empty());
+    assert!(search("%20").await.is_empty());
+
+    let (_, results) = app.get("/api/users/search?q=ameo&limit=1").await;
+    assert_eq!(results.as_array().unwrap().len(), 1);
+    assert_eq!(results[0]["id"], FIXTURE_USER_ID);
+    assert_eq!(results[0]["country"], "US");
+    assert_eq!(results[0]["global_rank_4k"], 7961);
+    assert_eq!(results[0]["global_rank_7k"], 38698);
+
+    assert_eq!(app.mock.requests().len(), requests_before);
+}
@@ -0,0 +1,252 @@
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use diesel::{pg::PgConnection, prelude::*};
+use libquavertrack::{
+    api::{mock::MockQuaverAPI, QuaverClient},
+    db_util::schema,
+};
+use rocket::{
+    figment::Figment,
+    http::Status,
+    local::asynchronous::{Client, LocalResponse},
+};
+use serde_json::Value;
+
+use crate::DbConn;
+
+mod cooldowns;
+mod lookup;
+mod profiles;
+mod scores;
+mod stats_history;
+mod updates;
+mod upstream;
+
+embed_migrations!("../libquavertrack/migrations");
+
+const FIXTURES_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../libquavertrack/fixtures");
+const TEST_DATABASE_URL_VAR: &str = "QUAVERTRACK_TEST_DATABASE_URL";
+/// User that has fixtures for every endpoint
+const FIXTURE_USER_ID: i64 = 19250;
+const UPDATE_TOKEN: &str = "test-token";
+
+static DATABASE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A freshly migrated database that's dropped again once the test using it is done.
+///
+/// Tests that need a database are ignored by default.  To run them, point
+/// `QUAVERTRACK_TEST_DATABASE_URL` at a Postgres database whose user is allowed to create
+/// databases, e.g. `QUAVERTRACK_TEST_DATABASE_URL=postgres://postgres@localhost/postgres cargo test
+/// -- --ignored`.
+struct TestDatabase {
+    admin_url: String,
+    name: String,
+    url: String,
+}
+
+impl TestDatabase {
+    fn create() -> Self {
+        let admin_url = std::env::var(TEST_DATABASE_URL_VAR)
+            .unwrap_or_else(|_| panic!("{} must be set to run this test", TEST_DATABASE_URL_VAR));
+        let name = format!(
+            "quavertrack_test_{}_{}",
+            std::process::id(),
+            DATABASE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let server_url = admin_url
+            .rsplit_once('/')
+            .map(|(server_url, _)| server_url)
+            .unwrap_or(&admin_url);
+        let url = format!("{}/{}", server_url, name);
+
+        let admin_conn =
+            PgConnection::establish(&admin_url).expect("Failed to connect to Postgres");
+        admin_conn
+            .execute(&format!("DROP DATABASE IF EXISTS {}", name))
+            .unwrap();
+        admin_conn
+            .execute(&format!("CREATE DATABASE {}", name))
+            .unwrap();
+
+        let db = TestDatabase {
+            admin_url,
+            name,
+            url,
+        };
+        embedded_migrations::run(&db.conn()).expect("Failed to run migrations");
+        db
+    }
+
+    fn conn(&self) -> PgConnection {
+        PgConnection::establish(&self.url).expect("Failed to connect to test database")
+    }
+}
+
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        let res = PgConnection::establish(&self.admin_url).and_then(|conn| {
+            conn.execute(&format!(
+                "DROP DATABASE IF EXISTS {} WITH (FORCE)",
+                self.name
+            ))
+            .map_err(|err| diesel::ConnectionError::BadConnection(err.to_string()))
+        });
+        if let Err(err) = res {
+            eprintln!("Failed to drop test database {}: {}", self.name, err);
+        }
+    }
+}
+
+/// The whole backend running against a mock Quaver API and a throwaway database.  Fields are
+/// dropped in order, so the app shuts down before its database goes away.
+struct TestApp {
+    client: Client,
+    mock: MockQuaverAPI,
+    db: TestDatabase,
+}
+
+impl TestApp {
+    async fn start() -> Self {
+        Self::start_with(|figment| figment).await
+    }
+
+    /// Starts the app with extra configuration applied by `configure`
+    async fn start_with(configure: impl FnOnce(Figment) -> Figment) -> Self {
+        let db = TestDatabase::create();
+        let mock = MockQuaverAPI::start(FIXTURES_DIR).await.unwrap();
+
+        let figment = rocket::Config::figment()
+            .merge(("databases.quavertrack.url", &db.url))
+            .merge(("quaver_api_base_url", mock.base_url()))
+            .merge(("update_tokens", [UPDATE_TOKEN]))
+            // Lets tests update the same user repeatedly from different clients
+            .merge(("min_seconds_between_user_updates", 0))
+            .merge(("scheduler.enabled", false))
+            .merge(("retention.enabled", false));
+        let client = Client::tracked(crate::build_rocket(configure(figment)))
+            .await
+            .expect("Failed to build Rocket");
+
+        TestApp { client, mock, db }
+    }
+
+    async fn db_conn(&self) -> DbConn {
+        DbConn::get_one(self.client.rocket())
+            .await
+            .expect("No database connection available")
+    }
+
+    fn quaver_client(&self) -> &QuaverClient {
+        self.client.rocket().state().unwrap()
+    }
+
+    /// Triggers an update of `user` as if it came from the client at `remote`
+    async fn update(&self, user: &str, remote: &str) -> (Status, Value) {
+        let res = self
+            .client
+            .post(format!("/api/update/{}", user))
+            .remote(remote.parse::<SocketAddr>().unwrap())
+            .dispatch()
+            .await;
+        read_json(res).await
+    }
+
+    async fn get(&self, uri: &str) -> (Status, Value) {
+        read_json(self.client.get(uri.to_owned()).dispatch().await).await
+    }
+
+    /// Gets `uri` and every page after it, returning the items from all of them in order
+    async fn get_all_pages(&self, uri: &str, pagination: &Pagination) -> Vec<Value> {
+        let separator = if uri.contains('?') { '&' } else { '?' };
+        let mut items = Vec::new();
+        let mut page_uri = uri.to_owned();
+        loop {
+            let (status, page) = self.get(&page_uri).await;
+            assert_eq!(status, Status::Ok, "{}: {}", page_uri, page);
+            items.extend(page[pagination.items].as_array().unwrap().iter().cloned());
+
+            let cursor = match &page[pagination.next] {
+                Value::Null => return items,
+                Value::String(cursor) => cursor.clone(),
+                cursor => cursor.to_string(),
+            };
+            page_uri = format!("{}{}{}={}", uri, separator, pagination.param, cursor);
+        }
+    }
+
+    /// Stores `count` copies of the only 4K stats snapshot, `interval` apart starting at `start`
+    fn copy_4k_snapshot(&self, count: i64, start: &str, interval: &str) {
+        let conn = self.db.conn();
+        diesel::sql_query(
+            "INSERT INTO stats_updates
+            SELECT copy.*
+            FROM stats_updates original, generate_series(0, $1 - 1) n,
+                LATERAL jsonb_populate_record(
+                    NULL::stats_updates,
+                    to_jsonb(original) || jsonb_build_object(
+                        'id', nextval('stats_updates_id_seq'),
+                        'recorded_at', $2::timestamp + n * $3::interval
+                    )
+                ) copy
+            WHERE original.mode = 1",
+        )
+        .bind::<diesel::sql_types::BigInt, _>(count)
+        .bind::<diesel::sql_types::Text, _>(start)
+        .bind::<diesel::sql_types::Text, _>(interval)
+        .execute(&conn)
+        .unwrap();
+    }
+
+    fn count_rows(&self, table: &str) -> i64 {
+        let conn = self.db.conn();
+        match table {
+            "scores" => schema::scores::table.count().get_result(&conn),
+            "stats_updates" => schema::stats_updates::table.count().get_result(&conn),
+            "users" => schema::users::table.count().get_result(&conn),
+            "user_profile_history" => schema::user_profile_history::table
+                .count()
+                .get_result(&conn),
+            _ => panic!("Unknown table {}", table),
+        }
+        .unwrap()
+    }
+}
+
+/// Where a cursor-paginated endpoint puts the items on each page and the cursor for the next one,
+/// and which query parameter that cursor is passed back as
+struct Pagination {
+    items: &'static str,
+    next: &'static str,
+    param: &'static str,
+}
+
+const STATS_HISTORY_PAGES: Pagination = Pagination {
+    items: "updates",
+    next: "next_cursor",
+    param: "cursor",
+};
+
+const ACTIVITY_PAGES: Pagination = Pagination {
+    items: "events",
+    next: "next_before",
+    param: "before",
+};
+
+/// `recorded_at` of each of a list of stats snapshots
+fn recorded_at(updates: &[Value]) -> Vec<String> {
+    updates
+        .iter()
+        .map(|update| update["recorded_at"].as_str().unwrap().to_owned())
+        .collect()
+}
+
+async fn read_json(res: LocalResponse<'_>) -> (Status, Value) {
+    let status = res.status();
+    let body = res.into_string().await.unwrap_or_default();
+    let json = serde_json::from_str(&body)
+        .unwrap_or_else(|_| panic!("Response with status {} wasn't JSON: {}", status, body));
+    (status, json)
+}
@@ -0,0 +1,148 @@
+use diesel::prelude::*;
+use libquavertrack::db_util::{
+    self,
+    models::{APIProfileBadge, DBUser, DBUserBadge},
+    schema::{user_badges, user_profile_history, users},
+};
+use rocket::http::Status;
+use serde_json::Value;
+
+use super::{TestApp, FIXTURES_DIR, FIXTURE_USER_ID};
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn badge_changes() {
+    let app = TestApp::start().await;
+
+    let (status, _) = app.update("ameo", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::Ok);
+    let conn = app.db.conn();
+    let badge = |id: i64| APIProfileBadge {
+        id,
+        name: format!("Badge {}", id),
+        description: None,
+    };
+    let badge_ids = |badges: &[DBUserBadge]| {
+        badges
+            .iter()
+            .map(|badge| badge.badge_id)
+            .collect::<Vec<_>>()
+    };
+
+    // Badges a user already had when we first see them weren't just gained, and duplicates from
+    // upstream are ignored
+    let changes = db_util::store_badges(
+        &conn,
+        FIXTURE_USER_ID,
+        vec![badge(1), badge(2), badge(1)],
+        true,
+    )
+    .unwrap();
+    assert!(changes.gained.is_empty());
+    assert!(changes.removed.is_empty());
+
+    let changes = db_util::store_badges(&conn, FIXTURE_USER_ID, vec![badge(1)], false).unwrap();
+    assert_eq!(badge_ids(&changes.removed), vec![2]);
+
+    // Badges that come back are gained again
+    let changes =
+        db_util::store_badges(&conn, FIXTURE_USER_ID, vec![badge(1), badge(2)], false).unwrap();
+    assert_eq!(badge_ids(&changes.gained), vec![2]);
+    assert_eq!(
+        changes.gained[0].first_seen_at,
+        changes.gained[0].last_seen_at
+    );
+    let kept: DBUserBadge = user_badges::table
+        .find((FIXTURE_USER_ID, 1))
+        .first(&conn)
+        .unwrap();
+    assert!(kept.first_seen_at < kept.last_seen_at);
+}
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn first_badge_of_tracked_user_is_gained() {
+    let app = TestApp::start().await;
+
+    // The fixture user has no badges
+    let (status, update) = app.update("ameo", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(update["badge_changes"]["gained"], serde_json::json!([]));
+    let (status, update) = app.update("ameo", "10.0.0.2:1234").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(update["badge_changes"]["gained"], serde_json::json!([]));
+
+    let mut with_badge: Value = serde_json::from_str(
+        &std::fs::read_to_string(format!("{}/v1/users/full/19250.json", FIXTURES_DIR)).unwrap(),
+    )
+    .unwrap();
+    with_badge["user"]["profile_badges"] = serde_json::json!([
+        { "id": 7, "name": "Donator", "description": null }
+    ]);
+    app.mock
+        .enqueue("/v1/users/full/19250/", 200, with_badge.to_string());
+    let (status, update) = app.update("ameo", "10.0.0.3:1234").await;
+    assert_eq!(status, Status::Ok);
+    let gained = update["badge_changes"]["gained"].as_array().unwrap();
+    assert_eq!(gained.len(), 1);
+    assert_eq!(gained[0]["badge_id"], 7);
+}
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn profile_changes_are_recorded() {
+    let app = TestApp::start().await;
+
+    let (status, _) = app.update("ameo", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::Ok);
+    let user: DBUser = users::table
+        .find(FIXTURE_USER_ID)
+        .first(&app.db.conn())
+        .unwrap();
+    assert_eq!(user.privileges, Some(1));
+    assert_eq!(user.online, Some(false));
+    assert!(user.latest_activity.is_some());
+    assert_eq!(app.count_rows("user_profile_history"), 1);
+
+    // Unchanged profiles don't add to the history, and fields missing from a response are kept
+    let mut without_groups: Value = serde_json::from_str(
+        &std::fs::read_to_string(format!("{}/v1/users/full/19250.json", FIXTURES_DIR)).unwrap(),
+    )
+    .unwrap();
+    let info = without_groups["user"]["info"].as_object_mut().unwrap();
+    info.remove("privileges");
+    info.remove("usergroups");
+    app.mock
+        .enqueue("/v1/users/full/19250/", 200, without_groups.to_string());
+    let (status, _) = app.update("ameo", "10.0.0.2:1234").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(app.count_rows("user_profile_history"), 1);
+    let user: DBUser = users::table
+        .find(FIXTURE_USER_ID)
+        .first(&app.db.conn())
+        .unwrap();
+    assert_eq!((user.privileges, user.usergroups), (Some(1), Some(1)));
+
+    let renamed = std::fs::read_to_string(format!("{}/v1/users/full/19250.json", FIXTURES_DIR))
+        .unwrap()
+        .replace(r#""username": "ameo""#, r#""username": "Ameo_Renamed""#)
+        .replace(r#""country": "US""#, r#""country": "CA""#);
+    app.mock.enqueue("/v1/users/full/19250/", 200, &renamed);
+    let (status, _) = app.update("ameo", "10.0.0.3:1234").await;
+    assert_eq!(status, Status::Ok);
+
+    let user: DBUser = users::table
+        .find(FIXTURE_USER_ID)
+        .first(&app.db.conn())
+        .unwrap();
+    assert_eq!(
+        (user.username.as_str(), user.country.as_str()),
+        ("ameo_renamed", "CA")
+    );
+    let usernames: Vec<String> = user_profile_history::table
+        .order_by(user_profile_history::dsl::recorded_at)
+        .select(user_profile_history::dsl::username)
+        .load(&app.db.conn())
+        .unwrap();
+    assert_eq!(usernames, vec!["ameo", "ameo_renamed"]);
+}
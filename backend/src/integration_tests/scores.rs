@@ -0,0 +1,155 @@
+use diesel::prelude::*;
+use libquavertrack::db_util::{
+    models::{DBScore, Mods},
+    schema::scores,
+};
+use rocket::http::Status;
+use serde_json::Value;
+
+use super::TestApp;
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn map_score_history() {
+    let app = TestApp::start().await;
+
+    let (status, _) = app.update("ameo", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::Ok);
+    // A later attempt on the same map that doesn't beat the personal best
+    let conn = app.db.conn();
+    let mut score: DBScore = scores::table.find(1046201).first(&conn).unwrap();
+    score.id = 1046500;
+    score.time += chrono::Duration::minutes(30);
+    score.performance_rating = 10.2;
+    diesel::insert_into(scores::table)
+        .values(&score)
+        .execute(&conn)
+        .unwrap();
+
+    let (status, history) = app.get("/api/user/ameo/4k/maps/2257/history").await;
+    assert_eq!(status, Status::Ok, "{}", history);
+    assert_eq!(history["map"]["id"], 2257);
+    let scores = history["scores"].as_array().unwrap();
+    let summary = scores
+        .iter()
+        .map(|score| {
+            (
+                score["id"].as_i64().unwrap(),
+                score["new_personal_best"].as_bool().unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        vec![(1045977, true), (1046201, true), (1046500, false)]
+    );
+    assert_eq!(scores[0]["improvement"], Value::Null);
+    assert_eq!(scores[1]["improvement"]["previous_best_id"], 1045977);
+    assert!(
+        (scores[1]["improvement"]["performance_rating"]
+            .as_f64()
+            .unwrap()
+            - 0.7)
+            .abs()
+            < 1e-4
+    );
+    assert_eq!(scores[2]["improvement"], Value::Null);
+
+    let (status, history) = app.get("/api/user/ameo/7k/maps/2257/history").await;
+    assert_eq!(status, Status::Ok);
+    assert!(history["scores"].as_array().unwrap().is_empty());
+    let (status, body) = app.get("/api/user/ameo/4k/maps/1/history").await;
+    assert_eq!(status, Status::NotFound);
+    assert_eq!(body["error"], "map_not_found");
+}
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn top_scores_per_map() {
+    let app = TestApp::start().await;
+
+    let (status, _) = app.update("ameo", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::Ok);
+    // Upstream flagged 1046201 as a personal best when it was fetched, but it's beaten by a later
+    // score.  There's also a worse play on the same map with mirror.
+    let conn = app.db.conn();
+    for (id, rating, mods) in &[(1046600, 11., Mods::NONE), (1046700, 9., Mods::MIRROR)] {
+        let mut score: DBScore = scores::table.find(1046201).first(&conn).unwrap();
+        score.id = *id;
+        score.time += chrono::Duration::hours(1);
+        score.performance_rating = *rating;
+        score.mods = *mods;
+        diesel::insert_into(scores::table)
+            .values(&score)
+            .execute(&conn)
+            .unwrap();
+    }
+
+    let app = &app;
+    let top_scores = |query: &'static str| async move {
+        let (status, top) = app
+            .get(&format!("/api/user/ameo/4k/top_scores{}", query))
+            .await;
+        assert_eq!(status, Status::Ok, "{}", top);
+        assert!(top["maps"]["2257"].is_object());
+        top["scores"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|score| {
+                (
+                    score["id"].as_i64().unwrap(),
+                    score["play_count"].as_i64().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(top_scores("").await, vec![(1046388, 1), (1046600, 4)]);
+    assert_eq!(
+        top_scores("?per_mods=true").await,
+        vec![(1046388, 1), (1046600, 4), (1046700, 4)]
+    );
+}
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn rating_what_if() {
+    let app = TestApp::start().await;
+
+    let (status, _) = app.update("ameo", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::Ok);
+
+    let app = &app;
+    let what_if = |query: &'static str| async move {
+        let (status, body) = app
+            .get(&format!("/api/user/ameo/4k/rating/what_if?{}", query))
+            .await;
+        assert_eq!(status, Status::Ok, "{}", body);
+        body
+    };
+    let close = |value: &Value, expected: f64| (value.as_f64().unwrap() - expected).abs() < 1e-3;
+
+    // Only the best score on each of the two maps played counts
+    let new_map = what_if("rating=12").await;
+    assert_eq!(new_map["scores_used"], 2);
+    assert!(close(&new_map["computed_rating"], 14.02 + 10.5 * 0.95));
+    assert!(close(
+        &new_map["gain"],
+        12. * 0.95 + 10.5 * 0.95 * 0.95 - 10.5 * 0.95
+    ));
+    assert!(close(
+        &new_map["projected_rating"],
+        new_map["upstream_rating"].as_f64().unwrap() + new_map["gain"].as_f64().unwrap()
+    ));
+    // A better score on a map that's already been played replaces the old one
+    assert!(close(
+        &what_if("rating=12&map_id=2257").await["gain"],
+        1.5 * 0.95
+    ));
+    assert!(close(&what_if("rating=5&map_id=2257").await["gain"], 0.));
+
+    let (status, body) = app.get("/api/user/ameo/4k/rating/what_if").await;
+    assert_eq!(status, Status::BadRequest);
+    assert_eq!(body["error"], "invalid_rating");
+}
@@ -0,0 +1,257 @@
+use diesel::prelude::*;
+use libquavertrack::db_util;
+use rocket::http::Status;
+use serde_json::Value;
+
+use super::{recorded_at, TestApp, STATS_HISTORY_PAGES};
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn stats_history_pagination_and_resolution() {
+    let app = TestApp::start().await;
+
+    let (status, _) = app.update("ameo", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::Ok);
+    app.copy_4k_snapshot(10, "2021-01-01", "20 minutes");
+
+    let app = &app;
+    let history = |query: &'static str| async move {
+        let (status, history) = app
+            .get(&format!("/api/user/ameo/4k/stats_history?{}", query))
+            .await;
+        assert_eq!(status, Status::Ok, "{}: {}", query, history);
+        (
+            recorded_at(history["updates"].as_array().unwrap()),
+            history["next_cursor"].clone(),
+        )
+    };
+
+    let (all, next_cursor) = history("").await;
+    assert_eq!(all.len(), 11);
+    assert_eq!(next_cursor, Value::Null);
+
+    // Hourly buckets keep the last snapshot of each hour, and paging through them with the cursor
+    // returns each bucket exactly once
+    let (hourly, _) = history("resolution=hourly").await;
+    assert_eq!(
+        &hourly[..4],
+        &[
+            "2021-01-01T00:40:00",
+            "2021-01-01T01:40:00",
+            "2021-01-01T02:40:00",
+            "2021-01-01T03:00:00"
+        ]
+    );
+    assert_eq!(hourly.len(), 5);
+    let paged = app
+        .get_all_pages(
+            "/api/user/ameo/4k/stats_history?resolution=hourly&limit=2",
+            &STATS_HISTORY_PAGES,
+        )
+        .await;
+    assert_eq!(recorded_at(&paged), hourly);
+
+    // `from` and `to` are inclusive milliseconds since the epoch
+    let (range, _) = history("from=1609462800000&to=1609466400000").await;
+    assert_eq!(
+        range,
+        vec![
+            "2021-01-01T01:00:00",
+            "2021-01-01T01:20:00",
+            "2021-01-01T01:40:00",
+            "2021-01-01T02:00:00"
+        ]
+    );
+    let (daily, _) = history("resolution=daily&to=1609545600000").await;
+    assert_eq!(daily, vec!["2021-01-01T03:00:00"]);
+
+    let (status, body) = app
+        .get("/api/user/ameo/4k/stats_history?resolution=yearly")
+        .await;
+    assert_eq!(status, Status::BadRequest);
+    assert_eq!(body["error"], "invalid_resolution");
+}
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn stats_history_pages_split_between_identical_timestamps() {
+    let app = TestApp::start().await;
+
+    let (status, _) = app.update("ameo", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::Ok);
+    app.copy_4k_snapshot(3, "2021-01-01", "0 minutes");
+
+    // The first page ends between snapshots recorded at the same time
+    let paged = app
+        .get_all_pages(
+            "/api/user/ameo/4k/stats_history?limit=2",
+            &STATS_HISTORY_PAGES,
+        )
+        .await;
+    let paged = recorded_at(&paged);
+    assert_eq!(paged.len(), 4);
+    assert_eq!(&paged[..3], &["2021-01-01T00:00:00"; 3]);
+}
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn stats_history_cursor_outlives_its_snapshot() {
+    let app = TestApp::start().await;
+
+    let (status, _) = app.update("ameo", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::Ok);
+    app.copy_4k_snapshot(4, "2021-01-01", "1 hour");
+
+    let (status, first_page) = app.get("/api/user/ameo/4k/stats_history?limit=2").await;
+    assert_eq!(status, Status::Ok);
+    let cursor = first_page["next_cursor"].as_str().unwrap();
+
+    // The last snapshot on the first page goes away, e.g. because it was rolled up and pruned
+    diesel::sql_query("DELETE FROM stats_updates WHERE recorded_at = '2021-01-01 01:00:00'")
+        .execute(&app.db.conn())
+        .unwrap();
+    let (status, second_page) = app
+        .get(&format!(
+            "/api/user/ameo/4k/stats_history?limit=2&cursor={}",
+            cursor
+        ))
+        .await;
+    assert_eq!(status, Status::Ok, "{}", second_page);
+    assert_eq!(
+        recorded_at(second_page["updates"].as_array().unwrap()),
+        vec!["2021-01-01T02:00:00", "2021-01-01T03:00:00"]
+    );
+
+    let (status, body) = app
+        .get("/api/user/ameo/4k/stats_history?cursor=12345")
+        .await;
+    assert_eq!(status, Status::BadRequest);
+    assert_eq!(body["error"], "invalid_cursor");
+}
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn old_stats_are_rolled_up() {
+    let app = TestApp::start().await;
+
+    let (status, _) = app.update("ameo", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::Ok);
+    // Three snapshots a day from Monday 2021-01-04 through Wednesday 2021-01-13
+    app.copy_4k_snapshot(30, "2021-01-04", "8 hours");
+
+    let app = &app;
+    let history = |query: &'static str| async move {
+        let (status, history) = app
+            .get(&format!("/api/user/ameo/4k/stats_history?{}", query))
+            .await;
+        assert_eq!(status, Status::Ok, "{}", history);
+        recorded_at(history["updates"].as_array().unwrap())
+    };
+    let weekly_before = history("resolution=weekly").await;
+
+    // Everything before Wednesday is rolled up: the first week down to its first and last
+    // snapshots, then Monday and Tuesday down to theirs
+    let daily_cutoff = chrono::NaiveDate::from_ymd_opt(2021, 1, 13)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap();
+    let weekly_cutoff = daily_cutoff - chrono::Duration::days(2);
+    let conn = app.db.conn();
+    let counts = db_util::roll_up_stats_updates(&conn, daily_cutoff, weekly_cutoff, None).unwrap();
+    assert_eq!(
+        counts,
+        db_util::RollupCounts {
+            rolled_up: 27,
+            pruned: 21,
+        }
+    );
+    for previous_weekly_cutoff in [None, Some(weekly_cutoff)] {
+        assert_eq!(
+            db_util::roll_up_stats_updates(
+                &conn,
+                daily_cutoff,
+                weekly_cutoff,
+                previous_weekly_cutoff
+            )
+            .unwrap(),
+            db_util::RollupCounts::default()
+        );
+    }
+
+    // History transparently includes rolled up snapshots, and paging works across both tables
+    let raw = history("").await;
+    assert_eq!(
+        &raw[..6],
+        &[
+            "2021-01-04T00:00:00",
+            "2021-01-10T16:00:00",
+            "2021-01-11T00:00:00",
+            "2021-01-11T16:00:00",
+            "2021-01-12T00:00:00",
+            "2021-01-12T16:00:00",
+        ]
+    );
+    assert_eq!(raw.len(), 10);
+    assert_eq!(history("resolution=weekly").await, weekly_before);
+    let paged = app
+        .get_all_pages(
+            "/api/user/ameo/4k/stats_history?limit=4",
+            &STATS_HISTORY_PAGES,
+        )
+        .await;
+    assert_eq!(recorded_at(&paged), raw);
+
+    // Users' latest snapshots are never rolled up, no matter how old they are
+    let far_future = chrono::Utc::now().naive_utc() + chrono::Duration::days(365);
+    let counts =
+        db_util::roll_up_stats_updates(&conn, far_future, weekly_cutoff, Some(weekly_cutoff))
+            .unwrap();
+    assert_eq!(counts.rolled_up, 3);
+    assert_eq!(app.count_rows("stats_updates"), 2);
+    assert_eq!(history("").await.len(), 9);
+}
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn rollups_catch_up_after_a_gap() {
+    let app = TestApp::start().await;
+
+    let (status, _) = app.update("ameo", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::Ok);
+    // Three snapshots a day for five weeks starting on Monday 2021-01-04
+    app.copy_4k_snapshot(105, "2021-01-04", "8 hours");
+    let weeks_in = |weeks: i64| {
+        chrono::NaiveDate::from_ymd_opt(2021, 1, 4)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            + chrono::Duration::weeks(weeks)
+    };
+
+    // Only the first week is kept at weekly resolution, the other four at daily resolution
+    let conn = app.db.conn();
+    let counts = db_util::roll_up_stats_updates(&conn, weeks_in(5), weeks_in(1), None).unwrap();
+    assert_eq!(
+        counts,
+        db_util::RollupCounts {
+            rolled_up: 105,
+            pruned: 105 - 2 - 28 * 2,
+        }
+    );
+
+    // The next run happens three weeks later, and thins out all three of the weeks that became
+    // weekly in between even though nothing new was rolled up
+    let counts =
+        db_util::roll_up_stats_updates(&conn, weeks_in(5), weeks_in(4), Some(weeks_in(1))).unwrap();
+    assert_eq!(
+        counts,
+        db_util::RollupCounts {
+            rolled_up: 0,
+            pruned: 3 * (14 - 2),
+        }
+    );
+    assert_eq!(
+        db_util::roll_up_stats_updates(&conn, weeks_in(5), weeks_in(4), None).unwrap(),
+        db_util::RollupCounts::default()
+    );
+}
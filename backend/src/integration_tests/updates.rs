@@ -0,0 +1,176 @@
+use diesel::prelude::*;
+use libquavertrack::{
+    db_util::{models::DBUser, schema::users},
+    scheduling::RefreshPolicy,
+};
+use rocket::http::Status;
+use serde_json::Value;
+
+use super::{read_json, TestApp, ACTIVITY_PAGES, FIXTURE_USER_ID, UPDATE_TOKEN};
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn update_then_read_back() {
+    let app = TestApp::start().await;
+
+    let (status, update) = app.update("ameo", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::Ok, "{}", update);
+    assert_eq!(update["stats"].as_array().unwrap().len(), 2);
+    // Recent and best scores overlap, so only 4 of the 6 scores returned are distinct
+    assert_eq!(update["new_scores"].as_array().unwrap().len(), 4);
+    assert!(app
+        .mock
+        .requests()
+        .contains(&"/v1/users/search/ameo".to_owned()));
+
+    let (status, scores) = app.get("/api/user/ameo/4k/scores").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(scores["scores"].as_array().unwrap().len(), 3);
+    assert_eq!(scores["maps"].as_object().unwrap().len(), 2);
+    let rate_score = scores["scores"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|score| score["id"] == 1046388)
+        .unwrap();
+    assert!((rate_score["mods"]["rate"].as_f64().unwrap() - 1.2).abs() < 1e-6);
+
+    let (status, history) = app.get("/api/user/ameo/7k/stats_history").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(history["updates"].as_array().unwrap().len(), 1);
+    assert_eq!(history["updates"][0]["play_count"], 1);
+    assert_eq!(history["next_cursor"], Value::Null);
+
+    let (status, activity) = app.get("/api/user/19250/activity").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(activity["events"].as_array().unwrap().len(), 5);
+    let paged = app
+        .get_all_pages("/api/user/19250/activity?limit=2", &ACTIVITY_PAGES)
+        .await;
+    assert_eq!(&paged, activity["events"].as_array().unwrap());
+
+    // Upstream returns the same stats again, so no new snapshots are stored
+    let (status, _) = app.update("ameo", "10.0.0.2:1234").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(app.count_rows("stats_updates"), 2);
+    assert_eq!(app.count_rows("scores"), 4);
+}
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn update_user_and_get_user_id() {
+    let app = TestApp::start().await;
+    let conn = app.db_conn().await;
+    let client = app.quaver_client();
+
+    // Unknown users are looked up upstream and stored
+    let (username, user_id) = crate::get_user_id(&conn, client, "ameo", false)
+        .await
+        .unwrap()
+        .found()
+        .unwrap();
+    assert_eq!((username.as_str(), user_id), ("ameo", FIXTURE_USER_ID));
+    assert_eq!(app.count_rows("users"), 1);
+
+    // ...after which they're found in the database by either username or ID
+    let requests_before = app.mock.requests().len();
+    for user in &["ameo", "19250"] {
+        let (_, user_id) = crate::get_user_id(&conn, client, user, false)
+            .await
+            .unwrap()
+            .found()
+            .unwrap();
+        assert_eq!(user_id, FIXTURE_USER_ID);
+    }
+    assert_eq!(app.mock.requests().len(), requests_before);
+
+    let update = crate::update_user(&conn, client, &RefreshPolicy::default(), FIXTURE_USER_ID)
+        .await
+        .unwrap();
+    assert_eq!(update.stats.len(), 2);
+    assert_eq!(update.new_scores.len(), 4);
+    assert!(update.badge_changes.gained.is_empty());
+}
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn rank_changes_dont_count_as_activity() {
+    let app = TestApp::start().await;
+    let conn = app.db_conn().await;
+    let client = app.quaver_client();
+    let policy = RefreshPolicy::default();
+    let refresh_interval = || {
+        let user: DBUser = users::table
+            .find(FIXTURE_USER_ID)
+            .first(&app.db.conn())
+            .unwrap();
+        user.next_update_at.unwrap() - user.last_updated_at.unwrap()
+    };
+
+    crate::update_user(&conn, client, &policy, FIXTURE_USER_ID)
+        .await
+        .unwrap();
+    let first_interval = refresh_interval();
+
+    // The player hasn't played in two weeks, but their ranks have moved since then
+    diesel::sql_query(
+        "UPDATE stats_updates
+        SET recorded_at = recorded_at - interval '14 days', global_rank = global_rank + 10",
+    )
+    .execute(&app.db.conn())
+    .unwrap();
+    crate::update_user(&conn, client, &policy, FIXTURE_USER_ID)
+        .await
+        .unwrap();
+    assert_eq!(app.count_rows("stats_updates"), 4);
+
+    let second_interval = refresh_interval();
+    assert!(
+        second_interval > first_interval * 10,
+        "{} <= {}",
+        second_interval,
+        first_interval
+    );
+}
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn bad_requests() {
+    let app = TestApp::start().await;
+
+    // The mode is checked before looking anyone up
+    for route in &["scores", "top_scores", "maps/1/history", "stats_history"] {
+        let (status, body) = app.get(&format!("/api/user/nobody/5k/{}", route)).await;
+        assert_eq!(status, Status::BadRequest, "{}", route);
+        assert_eq!(body["error"], "invalid_mode", "{}", route);
+    }
+    let (status, body) = app
+        .get("/api/user/nobody/5k/rating/what_if?rating=10")
+        .await;
+    assert_eq!(status, Status::BadRequest);
+    assert_eq!(body["error"], "invalid_mode");
+    assert!(app.mock.requests().is_empty());
+
+    let res = app
+        .client
+        .post("/api/update_oldest?token=wrong")
+        .dispatch()
+        .await;
+    let (status, body) = read_json(res).await;
+    assert_eq!(status, Status::Unauthorized);
+    assert_eq!(body["error"], "unauthorized");
+
+    // With the right token, the only user we know about gets updated
+    let (status, _) = app.update("ameo", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::Ok);
+    let res = app
+        .client
+        .post(format!("/api/update_oldest?token={}", UPDATE_TOKEN))
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(
+        res.into_string().await.unwrap(),
+        format!("Updated user id {}", FIXTURE_USER_ID)
+    );
+}
@@ -0,0 +1,167 @@
+use std::sync::atomic::Ordering;
+
+use libquavertrack::{
+    api::{
+        fixtures::FixtureMode, mock::MockQuaverAPI, APIError, QuaverClient, QuaverClientConfig,
+        UserLookup,
+    },
+    db_util::models::GameMode,
+    scheduling::RefreshPolicy,
+};
+use rocket::http::Status;
+
+use super::{TestApp, DATABASE_COUNTER, FIXTURES_DIR, FIXTURE_USER_ID};
+use crate::UpdateUserError;
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn upstream_user_not_found() {
+    let app = TestApp::start().await;
+
+    // Users that can't be found by ID or username
+    app.mock
+        .enqueue("/v1/users?id=404404", 200, r#"{"status":200,"users":[]}"#);
+    app.mock.enqueue(
+        "/v1/users/search/404404",
+        200,
+        r#"{"status":200,"users":[]}"#,
+    );
+    let (status, body) = app.update("404404", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::NotFound);
+    assert_eq!(body["error"], "user_not_found");
+
+    // Users that we know about but which have since disappeared upstream
+    let conn = app.db_conn().await;
+    let res = crate::update_user(&conn, app.quaver_client(), &RefreshPolicy::default(), 777).await;
+    assert!(matches!(res, Err(UpdateUserError::NotFound)));
+}
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn malformed_upstream_response() {
+    let app = TestApp::start().await;
+
+    app.mock.enqueue(
+        "/v1/users/scores/best?id=19250&mode=1",
+        200,
+        "{\"status\": 200, \"scores\": [",
+    );
+    let (status, body) = app.update("ameo", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::InternalServerError);
+    assert_eq!(body["error"], "bad_upstream_response");
+    // Details about what failed to decode are only logged
+    assert_eq!(body["message"], "Error reading data from Quaver API");
+
+    // Decode errors aren't retried and nothing gets stored for a failed update
+    let best_requests = app
+        .mock
+        .requests()
+        .into_iter()
+        .filter(|path| path == "/v1/users/scores/best?id=19250&mode=1")
+        .count();
+    assert_eq!(best_requests, 1);
+    assert_eq!(app.count_rows("stats_updates"), 0);
+    assert_eq!(app.count_rows("scores"), 0);
+}
+
+#[rocket::async_test]
+#[ignore = "requires QUAVERTRACK_TEST_DATABASE_URL"]
+async fn partial_upstream_failure() {
+    let app = TestApp::start().await;
+    let failing_path = "/v1/users/scores/recent?id=19250&mode=2";
+
+    // One endpoint failing on every attempt fails the whole update without storing anything
+    for _ in 0..4 {
+        app.mock.enqueue(failing_path, 500, "Internal Server Error");
+    }
+    let (status, body) = app.update("ameo", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::BadGateway);
+    assert_eq!(body["error"], "upstream_unavailable");
+    assert_eq!(app.count_rows("stats_updates"), 0);
+
+    // Transient failures are retried, and failed updates don't start the client's cooldown
+    app.mock.enqueue(failing_path, 503, "Service Unavailable");
+    let (status, body) = app.update("ameo", "10.0.0.1:1234").await;
+    assert_eq!(status, Status::Ok, "{}", body);
+    assert_eq!(app.count_rows("stats_updates"), 2);
+}
+
+#[rocket::async_test]
+async fn record_and_replay_fixtures() {
+    let mock = MockQuaverAPI::start(FIXTURES_DIR).await.unwrap();
+    let recorded_dir = std::env::temp_dir().join(format!(
+        "quavertrack_fixtures_{}_{}",
+        std::process::id(),
+        DATABASE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let recorder = QuaverClient::new(QuaverClientConfig {
+        base_url: mock.base_url(),
+        fixtures: FixtureMode::Record(recorded_dir.clone()),
+        ..QuaverClientConfig::default()
+    })
+    .unwrap();
+    let recorded_user = match recorder.lookup_user("ameo").await.unwrap() {
+        UserLookup::Found(user) => user,
+        other => panic!("Unexpected lookup result: {:?}", other),
+    };
+    let recorded_scores = recorder
+        .get_user_best_scores(FIXTURE_USER_ID, GameMode::Keys4)
+        .await
+        .unwrap()
+        .unwrap();
+
+    // Usernames are escaped, so they can't change the request or where it's recorded
+    let escaped_path = "/v1/users/search/..%2F..%2Fescaped%3Fx%23y";
+    mock.enqueue(escaped_path, 200, r#"{"status":200,"users":[]}"#);
+    let lookup = recorder.lookup_user("../../escaped?x#y").await.unwrap();
+    assert!(matches!(lookup, UserLookup::NotFound));
+    assert_eq!(mock.requests().last().unwrap(), escaped_path);
+    assert!(recorded_dir
+        .join("v1/users/search/..%2F..%2Fescaped%3Fx%23y.json")
+        .is_file());
+
+    // Replaying never touches the network, so point the client somewhere that doesn't exist
+    let requests_before = mock.requests().len();
+    let replayer = QuaverClient::new(QuaverClientConfig {
+        base_url: "http://127.0.0.1:1".to_owned(),
+        fixtures: FixtureMode::Replay(recorded_dir.clone()),
+        ..QuaverClientConfig::default()
+    })
+    .unwrap();
+    let replayed_user = match replayer.lookup_user("ameo").await.unwrap() {
+        UserLookup::Found(user) => user,
+        other => panic!("Unexpected lookup result: {:?}", other),
+    };
+    let replayed_scores = replayer
+        .get_user_best_scores(FIXTURE_USER_ID, GameMode::Keys4)
+        .await
+        .unwrap()
+        .unwrap();
+    let missing = replayer
+        .get_user_best_scores(FIXTURE_USER_ID, GameMode::Keys7)
+        .await;
+
+    // Responses are recorded verbatim
+    let fixture_name = "v1/users/scores/best/id=19250&mode=1.json";
+    let recorded_fixture = std::fs::read_to_string(recorded_dir.join(fixture_name));
+    std::fs::remove_dir_all(&recorded_dir).unwrap();
+    assert_eq!(
+        recorded_fixture.unwrap(),
+        std::fs::read_to_string(format!("{}/{}", FIXTURES_DIR, fixture_name)).unwrap()
+    );
+
+    assert_eq!(mock.requests().len(), requests_before);
+    assert_eq!(replayed_user.id, recorded_user.id);
+    assert_eq!(
+        replayed_scores
+            .iter()
+            .map(|score| score.id)
+            .collect::<Vec<_>>(),
+        recorded_scores
+            .iter()
+            .map(|score| score.id)
+            .collect::<Vec<_>>()
+    );
+    assert!(matches!(missing, Err(APIError::MissingFixture(_))));
+}
@@ -5,23 +5,40 @@ extern crate rocket;
 extern crate tokio;
 #[macro_use]
 extern crate log;
+#[cfg(test)]
+#[macro_use]
+extern crate diesel_migrations;
 
 use chrono::offset::Utc;
-use diesel::{pg::PgConnection, prelude::*};
+use diesel::pg::PgConnection;
 use fnv::FnvHashMap as HashMap;
+use futures::future;
 use libquavertrack::{
-    api::{self, APIError},
+    api::{APIError, QuaverClient, UserLookup},
     db_util::{
         self,
-        models::{DBScore, DBStatsUpdate, Map},
+        models::{
+            APIScore, BadgeChanges, DBScore, DBStatsUpdate, GameMode, Map, NewDBUser, UserCandidate,
+        },
     },
+    rating::RatedScores,
+    scheduling::{ActivitySignals, RefreshPolicy},
 };
+use rocket::{fairing::AdHoc, figment::Figment, Build, Rocket};
 use serde::Serialize;
 use thiserror::Error;
 
+use crate::{cooldown::UpdateCooldowns, error::RouteError, scheduler::Scheduler};
+
 mod conf;
+mod cooldown;
+mod error;
+#[cfg(test)]
+mod integration_tests;
 mod models;
+mod retention;
 mod routes;
+mod scheduler;
 
 #[rocket_sync_db_pools::database("quavertrack")]
 pub struct DbConn(PgConnection);
@@ -38,58 +55,107 @@ pub enum UpdateUserError {
 
 #[derive(Serialize)]
 pub struct UpdateData {
-    pub stats_4k: DBStatsUpdate,
-    pub stats_7k: DBStatsUpdate,
+    /// The newly recorded stats for each mode
+    pub stats: Vec<DBStatsUpdate>,
     pub maps: HashMap<i64, Map>,
     pub new_scores: Vec<DBScore>,
+    pub badge_changes: BadgeChanges,
 }
 
-pub async fn update_user(conn: &DbConn, user_id: i64) -> Result<UpdateData, UpdateUserError> {
-    let (user_stats, recent_4k_scores, best_4k_scores, recent_7k_scores, best_7k_scores) = tokio::try_join!(
-        async {
-            api::get_user_stats(user_id)
-                .await
-                .map_err(Into::into)
-                .and_then(|opt| opt.ok_or(UpdateUserError::NotFound))
-        },
-        async {
-            api::get_user_recent_scores(user_id, 1)
-                .await
-                .map_err(Into::into)
-                .and_then(|opt| opt.ok_or(UpdateUserError::NotFound))
-        },
-        async {
-            api::get_user_best_scores(user_id, 1)
-                .await
-                .map_err(Into::into)
-                .and_then(|opt| opt.ok_or(UpdateUserError::NotFound))
-        },
-        async {
-            api::get_user_recent_scores(user_id, 2)
-                .await
-                .map_err(Into::into)
-                .and_then(|opt| opt.ok_or(UpdateUserError::NotFound))
-        },
+/// Fetches both the recent and best scores for a user in the given mode
+async fn get_user_scores(
+    client: &QuaverClient,
+    user_id: i64,
+    mode: GameMode,
+) -> Result<Vec<APIScore>, UpdateUserError> {
+    let (recent_scores, best_scores) = tokio::try_join!(
+        client.get_user_recent_scores(user_id, mode),
+        client.get_user_best_scores(user_id, mode),
+    )?;
+
+    match (recent_scores, best_scores) {
+        (Some(recent_scores), Some(best_scores)) => Ok([recent_scores, best_scores].concat()),
+        _ => Err(UpdateUserError::NotFound),
+    }
+}
+
+/// Recomputes each mode's overall rating from the scores fetched in this update and logs a warning
+/// if it comes out higher than the one upstream reported in `stats`, which means that `RatedScores`
+/// no longer matches how upstream calculates it.  Upstream returns the user's best scores, so
+/// there's no need to load every score we have stored for them.
+fn check_overall_ratings(
+    user_id: i64,
+    stats: &[DBStatsUpdate],
+    fetched_scores: &[(GameMode, RatedScores)],
+) {
+    for update in stats {
+        let rated_scores = match fetched_scores.iter().find(|(mode, _)| *mode == update.mode) {
+            Some((_, rated_scores)) => rated_scores,
+            None => continue,
+        };
+        let check = rated_scores.check(update.overall_performance_rating);
+        if check.is_consistent() {
+            debug!(
+                "Overall rating of user {} in {:?}: {:?}",
+                user_id, update.mode, check
+            );
+        } else {
+            warn!(
+                "Computed overall rating of user {} in {:?} exceeds upstream's: {:?}",
+                user_id, update.mode, check
+            );
+        }
+    }
+}
+
+/// Fetches the latest stats and scores for a user and stores them, scheduling the user's next
+/// background refresh according to `refresh_policy`.
+pub async fn update_user(
+    conn: &DbConn,
+    client: &QuaverClient,
+    refresh_policy: &RefreshPolicy,
+    user_id: i64,
+) -> Result<UpdateData, UpdateUserError> {
+    let (mut user_stats, scores_by_mode) = tokio::try_join!(
         async {
-            api::get_user_best_scores(user_id, 2)
+            client
+                .get_user_stats(user_id)
                 .await
                 .map_err(Into::into)
                 .and_then(|opt| opt.ok_or(UpdateUserError::NotFound))
         },
+        future::try_join_all(
+            GameMode::ALL
+                .iter()
+                .map(|&mode| get_user_scores(client, user_id, mode))
+        ),
     )?;
 
-    let all_api_scores = [
-        recent_4k_scores,
-        best_4k_scores,
-        recent_7k_scores,
-        best_7k_scores,
-    ]
-    .concat();
+    let fetched_scores: Vec<(GameMode, RatedScores)> = GameMode::ALL
+        .iter()
+        .zip(&scores_by_mode)
+        .map(|(&mode, scores)| {
+            let (maps, scores): (Vec<Map>, Vec<DBScore>) = scores
+                .iter()
+                .cloned()
+                .map(|score| score.to_db(user_id))
+                .unzip();
+            (mode, RatedScores::new(&scores, &maps))
+        })
+        .collect();
+    let all_api_scores = scores_by_mode.concat();
+    let activity_feed = std::mem::take(&mut user_stats.activity_feed);
+    let profile_badges = std::mem::take(&mut user_stats.profile_badges);
+    let latest_activity = user_stats.info.latest_activity_at();
+    let profile: NewDBUser = user_stats.info.clone().into();
+    let refresh_policy = refresh_policy.clone();
 
     conn.run(move |conn| -> Result<UpdateData, UpdateUserError> {
-        use crate::db_util::schema::users;
-
         let do_inner = || -> Result<_, diesel::result::Error> {
+            let now = Utc::now().naive_utc();
+            let is_first_update = db_util::get_last_update_timestamp(conn, user_id)?.is_none();
+            db_util::store_user(conn, profile, now)?;
+
             let (maps, new_scores) = db_util::store_scores(&conn, user_id, all_api_scores)?;
 
             let mut maps_by_id = HashMap::default();
@@ -97,44 +163,102 @@ pub async fn update_user(conn: &DbConn, user_id: i64) -> Result<UpdateData, Upda
                 maps_by_id.insert(map.id, map);
             }
 
-            let [stats_4k, stats_7k] =
-                db_util::store_stats_update(&conn, user_stats).map(|updates| {
-                    let mut updates = updates.into_iter();
-                    [updates.next().unwrap(), updates.next().unwrap()]
-                })?;
+            db_util::store_activity_feed(conn, user_id, activity_feed)?;
+            let badge_changes =
+                db_util::store_badges(conn, user_id, profile_badges, is_first_update)?;
 
-            let now = Utc::now().naive_utc();
-            diesel::update(users::table.filter(users::dsl::id.eq(user_id)))
-                .set(users::dsl::last_updated_at.eq(now))
-                .execute(conn)?;
+            let stats = db_util::store_stats_update(conn, user_stats)?;
 
-            Ok((stats_4k, stats_7k, maps_by_id, new_scores))
+            db_util::set_last_updated_at(conn, user_id, now)?;
+
+            let activity_window = chrono::Duration::from_std(refresh_policy.activity_window)
+                .unwrap_or_else(|_| chrono::Duration::zero());
+            let mut last_stats_change = None;
+            for update in &stats {
+                let changed_at = db_util::get_player_stats_changed_at(conn, update)?;
+                last_stats_change = last_stats_change.max(Some(changed_at));
+            }
+            let signals = ActivitySignals {
+                recent_score_count: db_util::count_scores_since(
+                    conn,
+                    user_id,
+                    now - activity_window,
+                )?,
+                last_stats_change,
+                latest_activity,
+            };
+            db_util::set_next_update_at(
+                conn,
+                user_id,
+                refresh_policy.next_update_at(&signals, now),
+            )?;
+
+            Ok((stats, maps_by_id, new_scores, badge_changes))
         };
 
-        let (stats_4k, stats_7k, maps_by_id, new_scores) =
+        let (stats, maps_by_id, new_scores, badge_changes) =
             do_inner().map_err(|err| UpdateUserError::from(err))?;
 
+        check_overall_ratings(user_id, &stats, &fetched_scores);
+
         Ok(UpdateData {
-            stats_4k,
-            stats_7k,
+            stats,
             maps: maps_by_id,
             new_scores,
+            badge_changes,
         })
     })
     .await
 }
 
+/// What a username or ID provided by a client refers to
+pub enum ResolvedUser {
+    Found {
+        username: String,
+        user_id: i64,
+    },
+    /// Several users could have been meant
+    Ambiguous(Vec<UserCandidate>),
+    NotFound,
+}
+
+impl ResolvedUser {
+    /// Returns the username and ID of the user that was found, or the error to respond with if
+    /// there wasn't exactly one
+    pub fn found(self) -> Result<(String, i64), RouteError> {
+        match self {
+            ResolvedUser::Found { username, user_id } => Ok((username, user_id)),
+            ResolvedUser::Ambiguous(candidates) => Err(RouteError::AmbiguousUser(candidates)),
+            ResolvedUser::NotFound => Err(RouteError::UserNotFound),
+        }
+    }
+}
+
+/// Resolves a username or ID provided by a client to a user.  Users we know about are found by
+/// their current username or ID, and otherwise upstream is asked who currently has that name.
+/// Only if upstream doesn't know of anyone by that name are previous usernames of tracked users
+/// tried, so that a name that has since been taken by someone else resolves to its new owner.
+///
+/// If `suggest_similar` is set, lookups that don't match anyone exactly return users with similar
+/// names as candidates rather than `ResolvedUser::NotFound`.
 pub async fn get_user_id(
     conn: &DbConn,
+    client: &QuaverClient,
     user: &str,
-) -> Result<Option<(String, i64)>, UpdateUserError> {
+    suggest_similar: bool,
+) -> Result<ResolvedUser, UpdateUserError> {
     // Try to get by username first
     let user_clone = user.to_owned();
     match conn
         .run(move |conn| db_util::get_user_id_by_username(conn, &user_clone))
         .await?
     {
-        Some(user_id) => return Ok(Some((user.to_owned(), user_id))),
+        Some(user_id) => {
+            return Ok(ResolvedUser::Found {
+                username: user.to_lowercase(),
+                user_id,
+            })
+        }
         None => (),
     };
 
@@ -144,52 +268,146 @@ pub async fn get_user_id(
             .run(move |conn| db_util::get_username_by_user_id(conn, parsed_user_id))
             .await?
         {
-            Some(username) => return Ok(Some((username, parsed_user_id))),
+            Some(username) => {
+                return Ok(ResolvedUser::Found {
+                    username,
+                    user_id: parsed_user_id,
+                })
+            }
             None => (),
         }
     }
 
     // Hit the Quaver API to try to look this user up
-    match api::lookup_user(user).await? {
-        Some(mut user) => {
+    let upstream_candidates = match client.lookup_user(user).await? {
+        UserLookup::Found(mut user) => {
             let user_id = user.id;
             user.username = user.username.to_lowercase();
             let username = user.username.clone();
-            // Store user in DB
-            match conn.run(move |conn| db_util::store_user(conn, &user)).await {
-                Ok(_) => Ok(()),
-                Err(diesel::result::Error::DatabaseError(
-                    diesel::result::DatabaseErrorKind::UniqueViolation,
-                    _,
-                )) => {
-                    // User probably doesn't exactly match the username, but we already have
-                    // an entry in there anyway so just return the ID
-                    Ok(())
-                }
-                Err(err) => Err(err),
-            }?;
+            // Store user in DB, refreshing their profile if we already have them under a different
+            // username
+            let now = Utc::now().naive_utc();
+            conn.run(move |conn| db_util::store_user(conn, user.into(), now))
+                .await?;
 
-            Ok(Some((username, user_id)))
+            return Ok(ResolvedUser::Found { username, user_id });
         }
-        None => Ok(None),
+        UserLookup::Ambiguous(candidates) => candidates,
+        UserLookup::NotFound => Vec::new(),
+    };
+
+    // Try usernames that users have had in the past, ignoring case and accents
+    let user_clone = user.to_owned();
+    let mut previous_owners = conn
+        .run(move |conn| db_util::get_users_by_previous_username(conn, &user_clone))
+        .await?;
+    if previous_owners.len() > 1 {
+        return Ok(ResolvedUser::Ambiguous(previous_owners));
+    }
+    if let Some(owner) = previous_owners.pop() {
+        return Ok(ResolvedUser::Found {
+            username: owner.username,
+            user_id: owner.id,
+        });
+    }
+
+    if !suggest_similar {
+        return Ok(ResolvedUser::NotFound);
+    }
+    if !upstream_candidates.is_empty() {
+        return Ok(ResolvedUser::Ambiguous(upstream_candidates));
+    }
+
+    // Suggest tracked users with similar names in case this was a typo
+    let user_clone = user.to_owned();
+    let candidates = conn
+        .run(move |conn| db_util::search_users(conn, &user_clone, conf::MAX_USER_CANDIDATES))
+        .await?;
+
+    if candidates.is_empty() {
+        Ok(ResolvedUser::NotFound)
+    } else {
+        Ok(ResolvedUser::Ambiguous(candidates))
     }
 }
 
-#[rocket::main]
-pub async fn main() {
-    dotenv::dotenv().ok();
+/// Builds the application using configuration extracted from `figment`
+pub fn build_rocket(figment: Figment) -> Rocket<Build> {
+    let config: conf::Config = figment.extract().expect("Invalid configuration");
+    let db_pool_size = conf::db_pool_size(&figment).expect("Invalid database pool configuration");
+    config
+        .validate(db_pool_size)
+        .expect("Invalid configuration");
+    let client =
+        QuaverClient::new(config.client_config()).expect("Failed to build Quaver API client");
+    let scheduler_config = config.scheduler_config();
+    let retention_conf = config.retention.clone();
+    let retention_policy = config.retention_policy();
 
-    rocket::build()
+    rocket::custom(figment)
         .mount(
             "/api/",
             routes![
                 routes::update,
                 routes::get_stats_history,
                 routes::get_scores,
+                routes::get_top_scores,
+                routes::get_map_history,
+                routes::rating_what_if,
+                routes::get_activity,
+                routes::search_users,
                 routes::update_oldest
             ],
         )
         .attach(DbConn::fairing())
+        .manage(client)
+        .manage(UpdateCooldowns::new(
+            chrono::Duration::seconds(config.min_seconds_between_updates),
+            chrono::Duration::seconds(config.min_seconds_between_user_updates),
+        ))
+        .manage(config)
+        .manage(scheduler_config.refresh_policy.clone())
+        .attach(AdHoc::on_liftoff("Refresh scheduler", move |rocket| {
+            Box::pin(async move {
+                if !scheduler_config.enabled {
+                    info!("Refresh scheduler is disabled");
+                    return;
+                }
+
+                match Scheduler::new(rocket, scheduler_config).await {
+                    Some(scheduler) => {
+                        tokio::spawn(scheduler.run());
+                    }
+                    None => error!("Failed to start refresh scheduler"),
+                }
+            })
+        }))
+        .attach(AdHoc::on_liftoff("Stats retention", move |rocket| {
+            Box::pin(async move {
+                if !retention_conf.enabled {
+                    info!("Stats retention job is disabled");
+                    return;
+                }
+
+                match DbConn::get_one(rocket).await {
+                    Some(conn) => {
+                        tokio::spawn(retention::run(
+                            conn,
+                            retention_policy,
+                            std::time::Duration::from_secs(retention_conf.interval_secs),
+                        ));
+                    }
+                    None => error!("Failed to start stats retention job"),
+                }
+            })
+        }))
+}
+
+#[rocket::main]
+pub async fn main() {
+    dotenv::dotenv().ok();
+
+    build_rocket(rocket::Config::figment())
         .launch()
         .await
         .expect("Failed to launch Rocket");
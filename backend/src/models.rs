@@ -1,5 +1,7 @@
 use fnv::FnvHashMap as HashMap;
-use libquavertrack::db_util::models::{DBScore, Map};
+use libquavertrack::db_util::models::{
+    DBActivityEvent, DBScore, DBStatsUpdate, Map, ScoreHistoryEntry, TopScore,
+};
 use serde::Serialize;
 
 #[derive(Serialize)]
@@ -7,3 +9,47 @@ pub struct GetScoresResponse {
     pub maps: HashMap<i64, Map>,
     pub scores: Vec<DBScore>,
 }
+
+#[derive(Serialize)]
+pub struct GetTopScoresResponse {
+    pub maps: HashMap<i64, Map>,
+    /// One per map (or per map and mods), best first
+    pub scores: Vec<TopScore>,
+}
+
+#[derive(Serialize)]
+pub struct GetActivityResponse {
+    pub maps: HashMap<i64, Map>,
+    pub events: Vec<DBActivityEvent>,
+    /// Pass as `before` to fetch the next page; `None` if this is the last page
+    pub next_before: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct GetStatsHistoryResponse {
+    pub updates: Vec<DBStatsUpdate>,
+    /// Pass as `cursor` to fetch the next page; `None` if this is the last page
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct GetMapHistoryResponse {
+    pub map: Map,
+    /// Oldest first
+    pub scores: Vec<ScoreHistoryEntry>,
+}
+
+#[derive(Serialize)]
+pub struct RatingWhatIfResponse {
+    /// Overall rating from the user's latest stats snapshot
+    pub upstream_rating: Option<f64>,
+    /// Overall rating computed from the scores we have stored for the user
+    pub computed_rating: f64,
+    /// `computed_rating` including the hypothetical score
+    pub computed_rating_with_score: f64,
+    pub gain: f64,
+    /// `upstream_rating` plus `gain`
+    pub projected_rating: Option<f64>,
+    /// Number of stored scores that count towards `computed_rating`
+    pub scores_used: usize,
+}
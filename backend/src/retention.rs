@@ -0,0 +1,40 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use libquavertrack::{db_util, retention::RetentionPolicy};
+
+use crate::DbConn;
+
+/// Rolls up old stats snapshots according to `policy` every `interval`, forever.  The first run
+/// thins out all rolled up snapshots, and later ones only what changed since the last one that
+/// succeeded.
+pub async fn run(conn: DbConn, policy: RetentionPolicy, interval: Duration) {
+    info!("Starting stats retention job with policy {:?}", policy);
+
+    let mut previous_weekly_cutoff = None;
+    loop {
+        let (daily_cutoff, weekly_cutoff) = policy.cutoffs(Utc::now().naive_utc());
+        match conn
+            .run(move |conn| {
+                db_util::roll_up_stats_updates(
+                    conn,
+                    daily_cutoff,
+                    weekly_cutoff,
+                    previous_weekly_cutoff,
+                )
+            })
+            .await
+        {
+            Ok(counts) => {
+                info!(
+                    "Rolled up {} stats snapshots and pruned {} rolled up ones",
+                    counts.rolled_up, counts.pruned
+                );
+                previous_weekly_cutoff = Some(weekly_cutoff);
+            }
+            Err(err) => error!("Error rolling up stats snapshots: {:?}", err),
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
@@ -1,203 +1,334 @@
-use chrono::{offset::Utc, DateTime, NaiveDateTime};
+use chrono::{offset::Utc, NaiveDateTime};
 use fnv::FnvHashMap as HashMap;
-use libquavertrack::db_util::{self, models::DBStatsUpdate};
-use rocket::http::Status;
-use rocket::response::status;
+use libquavertrack::{
+    api::QuaverClient,
+    db_util::{
+        self,
+        models::{
+            GameMode, InvalidModeError, ScoreHistoryEntry, StatsHistoryCursor, StatsResolution,
+            UserSearchResult,
+        },
+        StatsHistoryQuery,
+    },
+    rating::RatedScores,
+    scheduling::RefreshPolicy,
+};
 use rocket::serde::json::Json;
+use rocket::State;
 
-use crate::models::GetScoresResponse;
+use crate::conf::Config;
+use crate::cooldown::{ClientIp, UpdateCooldowns};
+use crate::error::RouteError;
+use crate::models::{
+    GetActivityResponse, GetMapHistoryResponse, GetScoresResponse, GetStatsHistoryResponse,
+    GetTopScoresResponse, RatingWhatIfResponse,
+};
 use crate::DbConn;
 
-fn stringify_diesel_err(err: diesel::result::Error) -> status::Custom<&'static str> {
-    error!("Error querying DB: {:?}", err);
-    status::Custom(Status::InternalServerError, "Error querying database")
-}
-
-fn stringify_internal_err(err: crate::UpdateUserError) -> status::Custom<&'static str> {
-    error!("Error doing some internal operation: {:?}", err);
-    status::Custom(Status::InternalServerError, "Internal server error")
-}
-
-fn parse_mode(mode: &str) -> Result<i16, status::Custom<&'static str>> {
-    match mode {
-        "1" => Ok(1),
-        "2" => Ok(2),
-        "4" => Ok(1),
-        "7" => Ok(2),
-        "4k" => Ok(1),
-        "7k" => Ok(2),
-        "k4" => Ok(1),
-        "k7" => Ok(2),
-        _ => Err(status::Custom(Status::BadRequest, "Invalid mode provided")),
-    }
-}
-
 #[post("/update/<user>")]
 pub async fn update(
     user: String,
     conn: DbConn,
-) -> Result<Option<Json<crate::UpdateData>>, status::Custom<&'static str>> {
-    let (_username, user_id) = match crate::get_user_id(&conn, &user)
-        .await
-        .map_err(stringify_internal_err)?
-    {
-        Some(user_id) => user_id,
-        None => {
-            warn!("No user found in DB with username={}", user);
-            return Ok(None);
-        }
-    };
+    client: &State<QuaverClient>,
+    refresh_policy: &State<RefreshPolicy>,
+    cooldowns: &State<UpdateCooldowns>,
+    client_ip: ClientIp,
+) -> Result<Json<crate::UpdateData>, RouteError> {
+    let (_username, user_id) = crate::get_user_id(&conn, client, &user, false)
+        .await?
+        .found()?;
 
-    let last_update_time: Option<NaiveDateTime> = conn
+    let last_updated_at = conn
         .run(move |conn| db_util::get_last_update_timestamp(conn, user_id))
-        .await
-        .map_err(stringify_diesel_err)?;
-
-    let update_cooldown_remaining: Option<i64> = match last_update_time {
-        Some(last_update_time) => {
-            let now = Utc::now();
-            let last_update_time_utc: DateTime<Utc> = DateTime::from_utc(last_update_time, Utc);
-            let diff = now - last_update_time_utc;
-
-            if diff < chrono::Duration::seconds(crate::conf::MIN_SECONDS_BETWEEN_UPDATES) {
-                Some(diff.num_seconds())
-            } else {
-                None
-            }
-        }
-        None => None,
-    };
+        .await?;
+    let reservation = cooldowns.reserve(user_id, client_ip.0, last_updated_at, Utc::now())?;
 
-    if let Some(_) = update_cooldown_remaining {
-        return Err(status::Custom(
-            Status::InternalServerError,
-            "Updated too recently; must wait 10 seconds between updates",
-        ));
-    }
+    let stats_update = crate::update_user(&conn, client, refresh_policy, user_id).await?;
+    reservation.complete(Utc::now());
 
-    let stats_update = match crate::update_user(&conn, user_id).await {
-        Ok(stats_update) => Ok(stats_update),
-        Err(crate::UpdateUserError::NotFound) => {
-            error!("User not found when performing update");
-            return Ok(None);
-        }
-        Err(err) => Err(err),
-    }
-    .map_err(|err| {
-        error!("Error updating user: {:?}", err);
-        status::Custom(
-            Status::InternalServerError,
-            "Error updating user; internal error",
-        )
-    })?;
-
-    Ok(Some(Json(stats_update)))
+    Ok(Json(stats_update))
 }
 
 #[get("/user/<user>/<mode>/scores")]
 pub async fn get_scores(
     user: String,
-    mode: String,
+    mode: Result<GameMode, InvalidModeError>,
     conn: DbConn,
-) -> Result<Option<Json<GetScoresResponse>>, status::Custom<&'static str>> {
-    let (_username, user_id) = match crate::get_user_id(&conn, &user)
-        .await
-        .map_err(stringify_internal_err)?
-    {
-        Some(user_id) => user_id,
-        None => return Ok(None),
-    };
+    client: &State<QuaverClient>,
+) -> Result<Json<GetScoresResponse>, RouteError> {
+    let mode = mode?;
+    let (_username, user_id) = crate::get_user_id(&conn, client, &user, true)
+        .await?
+        .found()?;
 
-    let mode = parse_mode(&mode)?;
     let (maps, scores) = conn
         .run(move |conn| db_util::get_scores_for_user(&conn, user_id, mode))
-        .await
-        .map_err(stringify_diesel_err)?;
+        .await?;
+
+    let mut maps_by_id = HashMap::default();
+    for map in maps {
+        maps_by_id.insert(map.id, map);
+    }
+
+    Ok(Json(GetScoresResponse {
+        maps: maps_by_id,
+        scores,
+    }))
+}
+
+/// Returns only the user's best score on each map, or on each combination of map and mods if
+/// `per_mods` is set, along with how many times they've played each map.
+#[get("/user/<user>/<mode>/top_scores?<per_mods>")]
+pub async fn get_top_scores(
+    user: String,
+    mode: Result<GameMode, InvalidModeError>,
+    per_mods: Option<bool>,
+    conn: DbConn,
+    client: &State<QuaverClient>,
+) -> Result<Json<GetTopScoresResponse>, RouteError> {
+    let mode = mode?;
+    let (_username, user_id) = crate::get_user_id(&conn, client, &user, true)
+        .await?
+        .found()?;
+
+    let per_mods = per_mods.unwrap_or(false);
+    let (maps, scores) = conn
+        .run(move |conn| db_util::get_top_scores_for_user(conn, user_id, mode, per_mods))
+        .await?;
 
     let mut maps_by_id = HashMap::default();
     for map in maps {
         maps_by_id.insert(map.id, map);
     }
 
-    Ok(Some(Json(GetScoresResponse {
+    Ok(Json(GetTopScoresResponse {
         maps: maps_by_id,
         scores,
-    })))
+    }))
+}
+
+/// Returns every stored score a user has set on a map, oldest first, marking which ones were new
+/// personal bests and how much they improved on the previous one.
+#[get("/user/<user>/<mode>/maps/<map_id>/history")]
+pub async fn get_map_history(
+    user: String,
+    mode: Result<GameMode, InvalidModeError>,
+    map_id: i64,
+    conn: DbConn,
+    client: &State<QuaverClient>,
+) -> Result<Json<GetMapHistoryResponse>, RouteError> {
+    let mode = mode?;
+    let (_username, user_id) = crate::get_user_id(&conn, client, &user, true)
+        .await?
+        .found()?;
+
+    let (map, scores) = conn
+        .run(move |conn| db_util::get_map_scores_for_user(conn, user_id, mode, map_id))
+        .await?;
+
+    Ok(Json(GetMapHistoryResponse {
+        map: map.ok_or(RouteError::MapNotFound)?,
+        scores: ScoreHistoryEntry::from_scores(scores),
+    }))
 }
 
-#[get("/user/<user>/<mode>/stats_history")]
+/// Estimates how a user's overall rating would change if they set a new passing score rated
+/// `rating`, either on `map_id` or on a map they haven't played.  We don't have every score that
+/// counts towards the rating, so the gain is computed from the scores we do have and applied to the
+/// rating from their latest stats snapshot.
+#[get("/user/<user>/<mode>/rating/what_if?<rating>&<map_id>")]
+pub async fn rating_what_if(
+    user: String,
+    mode: Result<GameMode, InvalidModeError>,
+    rating: Option<f32>,
+    map_id: Option<i64>,
+    conn: DbConn,
+    client: &State<QuaverClient>,
+) -> Result<Json<RatingWhatIfResponse>, RouteError> {
+    let mode = mode?;
+    let (_username, user_id) = crate::get_user_id(&conn, client, &user, true)
+        .await?
+        .found()?;
+
+    let rating = rating
+        .filter(|rating| rating.is_finite() && *rating >= 0.)
+        .ok_or(RouteError::InvalidRating)?;
+    let (latest_stats, (maps, scores)) = conn
+        .run(move |conn| -> Result<_, diesel::result::Error> {
+            Ok((
+                db_util::get_latest_stats_update(conn, user_id, mode)?,
+                db_util::get_scores_for_user(conn, user_id, mode)?,
+            ))
+        })
+        .await?;
+
+    let rated_scores = RatedScores::new(&scores, &maps);
+    let computed_rating = rated_scores.overall_rating();
+    let computed_rating_with_score = rated_scores.overall_rating_with(map_id, rating);
+    let gain = computed_rating_with_score - computed_rating;
+    let upstream_rating = latest_stats.map(|stats| stats.overall_performance_rating as f64);
+
+    Ok(Json(RatingWhatIfResponse {
+        upstream_rating,
+        computed_rating,
+        computed_rating_with_score,
+        gain,
+        projected_rating: upstream_rating.map(|upstream| upstream + gain),
+        scores_used: rated_scores.len(),
+    }))
+}
+
+/// Parses a query parameter given as milliseconds since the Unix epoch
+fn parse_timestamp_millis(
+    name: &'static str,
+    millis: Option<i64>,
+) -> Result<Option<NaiveDateTime>, RouteError> {
+    millis
+        .map(|millis| {
+            NaiveDateTime::from_timestamp_opt(
+                millis.div_euclid(1000),
+                (millis.rem_euclid(1000) * 1_000_000) as u32,
+            )
+            .ok_or(RouteError::InvalidTimestamp(name))
+        })
+        .transpose()
+}
+
+/// Returns a page of a user's stats history, oldest first.  `from` and `to` are milliseconds since
+/// the Unix epoch.  `resolution` is one of raw (the default), hourly, daily or weekly; coarser
+/// resolutions return only the last snapshot in each bucket.
+#[get("/user/<user>/<mode>/stats_history?<from>&<to>&<limit>&<cursor>&<resolution>")]
+#[allow(clippy::too_many_arguments)]
 pub async fn get_stats_history(
     user: String,
-    mode: String,
+    mode: Result<GameMode, InvalidModeError>,
+    from: Option<i64>,
+    to: Option<i64>,
+    limit: Option<i64>,
+    cursor: Option<String>,
+    resolution: Option<String>,
     conn: DbConn,
-) -> Result<Option<Json<Vec<DBStatsUpdate>>>, status::Custom<&'static str>> {
-    let (_username, user_id) = match crate::get_user_id(&conn, &user)
-        .await
-        .map_err(stringify_internal_err)?
-    {
-        Some(user_id) => user_id,
-        None => return Ok(None),
-    };
+    client: &State<QuaverClient>,
+) -> Result<Json<GetStatsHistoryResponse>, RouteError> {
+    let mode = mode?;
+    let (_username, user_id) = crate::get_user_id(&conn, client, &user, true)
+        .await?
+        .found()?;
 
-    let mode = parse_mode(&mode)?;
+    let query = StatsHistoryQuery {
+        from: parse_timestamp_millis("from", from)?,
+        to: parse_timestamp_millis("to", to)?,
+        after: cursor.map(|cursor| cursor.parse()).transpose()?,
+        resolution: match resolution {
+            Some(resolution) => resolution.parse()?,
+            None => StatsResolution::default(),
+        },
+        limit: limit
+            .unwrap_or(crate::conf::DEFAULT_STATS_HISTORY_PAGE_SIZE)
+            .clamp(1, crate::conf::MAX_STATS_HISTORY_PAGE_SIZE),
+    };
+    let limit = query.limit;
     let updates = conn
-        .run(move |conn| db_util::get_stats_updates_for_user(conn, user_id, mode))
-        .await
-        .map_err(stringify_diesel_err)?;
+        .run(move |conn| db_util::get_stats_history_for_user(conn, user_id, mode, &query))
+        .await?;
+
+    let next_cursor = if updates.len() as i64 == limit {
+        updates
+            .last()
+            .map(|update| StatsHistoryCursor::from(update).to_string())
+    } else {
+        None
+    };
+
+    Ok(Json(GetStatsHistoryResponse {
+        updates,
+        next_cursor,
+    }))
+}
+
+#[get("/user/<user>/activity?<before>&<limit>")]
+pub async fn get_activity(
+    user: String,
+    before: Option<i64>,
+    limit: Option<i64>,
+    conn: DbConn,
+    client: &State<QuaverClient>,
+) -> Result<Json<GetActivityResponse>, RouteError> {
+    let (_username, user_id) = crate::get_user_id(&conn, client, &user, true)
+        .await?
+        .found()?;
+
+    let limit = limit
+        .unwrap_or(crate::conf::DEFAULT_ACTIVITY_PAGE_SIZE)
+        .clamp(1, crate::conf::MAX_ACTIVITY_PAGE_SIZE);
+    let (maps, events) = conn
+        .run(move |conn| db_util::get_activity_for_user(conn, user_id, before, limit))
+        .await?;
+
+    let mut maps_by_id = HashMap::default();
+    for map in maps {
+        maps_by_id.insert(map.id, map);
+    }
+    let next_before = if events.len() as i64 == limit {
+        events.last().map(|event| event.id)
+    } else {
+        None
+    };
+
+    Ok(Json(GetActivityResponse {
+        maps: maps_by_id,
+        events,
+        next_before,
+    }))
+}
+
+/// Searches users that we're tracking, for autocompleting usernames.  Never hits the Quaver API.
+#[get("/users/search?<q>&<limit>")]
+pub async fn search_users(
+    q: String,
+    limit: Option<i64>,
+    conn: DbConn,
+) -> Result<Json<Vec<UserSearchResult>>, RouteError> {
+    let query = q.trim().to_owned();
+    if query.is_empty() {
+        return Ok(Json(Vec::new()));
+    }
 
-    Ok(Some(Json(updates)))
+    let limit = limit
+        .unwrap_or(crate::conf::DEFAULT_USER_SEARCH_RESULTS)
+        .clamp(1, crate::conf::MAX_USER_SEARCH_RESULTS);
+    let now = Utc::now().naive_utc();
+    let results = conn
+        .run(move |conn| db_util::search_tracked_users(conn, &query, now, limit))
+        .await?;
+
+    Ok(Json(results))
 }
 
 #[post("/update_oldest?<token>")]
 pub async fn update_oldest(
     conn: DbConn,
+    client: &State<QuaverClient>,
+    refresh_policy: &State<RefreshPolicy>,
+    config: &State<Config>,
     token: String,
-) -> Result<String, status::Custom<&'static str>> {
-    if token.as_str() != env!("UPDATE_TOKEN") {
-        return Err(status::Custom(
-            Status::Unauthorized,
-            "Invalid update token provided",
-        ));
+) -> Result<String, RouteError> {
+    if !config.is_valid_update_token(&token) {
+        return Err(RouteError::Unauthorized);
     }
 
     let user_id_to_update = conn
         .run(|conn| crate::db_util::get_least_recently_updated_user_id(conn))
-        .await
-        .map_err(|err| {
-            error!("Error updating oldest user: {:?}", err);
-            status::Custom(
-                Status::InternalServerError,
-                "Internal error while updating oldest user",
-            )
-        })?;
-    if let Err(err) = crate::update_user(&conn, user_id_to_update).await {
-        error!("Error updating oldest user: {:?}", err);
-        return Err(match err {
-            crate::UpdateUserError::NotFound => {
-                conn.run(move |conn| {
-                    use crate::db_util::schema::users;
-                    use diesel::prelude::*;
-
-                    let now = Utc::now().naive_utc();
-                    diesel::update(users::table.filter(users::dsl::id.eq(user_id_to_update)))
-                        .set(users::dsl::last_updated_at.eq(now))
-                        .execute(conn)
-                })
-                .await
-                .map_err(|err| {
-                    error!("Error updating oldest user: {:?}", err);
-                    status::Custom(
-                        Status::InternalServerError,
-                        "Internal error while updating oldest user",
-                    )
-                })?;
-                status::Custom(Status::NotFound, "User not found from Quaver API")
-            }
-            _ => status::Custom(
-                Status::InternalServerError,
-                "Internal error while updating oldest user",
-            ),
-        });
+        .await?;
+    match crate::update_user(&conn, client, refresh_policy, user_id_to_update).await {
+        Ok(_) => (),
+        Err(crate::UpdateUserError::NotFound) => {
+            let now = Utc::now().naive_utc();
+            conn.run(move |conn| crate::db_util::set_last_updated_at(conn, user_id_to_update, now))
+                .await?;
+            return Err(RouteError::UserNotFound);
+        }
+        Err(err) => return Err(err.into()),
     }
 
     Ok(format!("Updated user id {}", user_id_to_update))
@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use futures::future;
+use libquavertrack::{api::QuaverClient, db_util, scheduling::RefreshPolicy};
+use rocket::{Orbit, Rocket};
+
+use crate::{DbConn, UpdateUserError};
+
+#[derive(Clone, Debug)]
+pub struct SchedulerConfig {
+    pub enabled: bool,
+    /// Number of users that are refreshed at the same time.  Each worker holds its own database
+    /// connection for as long as the app runs, so this must be smaller than the pool size.
+    pub concurrency: usize,
+    /// Decides how long to wait before refreshing each user again
+    pub refresh_policy: RefreshPolicy,
+    /// Maximum number of users refreshed per cycle
+    pub cycle_budget: usize,
+    /// How long to wait between cycles
+    pub cycle_interval: Duration,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            enabled: true,
+            concurrency: 2,
+            refresh_policy: RefreshPolicy::default(),
+            cycle_budget: 20,
+            cycle_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Keeps user stats fresh by continuously refreshing users once they're due according to the
+/// configured `RefreshPolicy`, so active players get refreshed more often than dormant ones.  All
+/// requests go through the shared `QuaverClient`, so the scheduler is subject to the same API rate
+/// limit as user-triggered updates.
+pub struct Scheduler {
+    config: SchedulerConfig,
+    client: QuaverClient,
+    conns: Vec<DbConn>,
+}
+
+impl Scheduler {
+    /// Builds a scheduler using the database pool and Quaver client managed by `rocket`.  Returns
+    /// `None` if either of them are missing or no database connections could be acquired.
+    pub async fn new(rocket: &Rocket<Orbit>, config: SchedulerConfig) -> Option<Self> {
+        let client = rocket.state::<QuaverClient>()?.clone();
+
+        let mut conns = Vec::with_capacity(config.concurrency);
+        for _ in 0..config.concurrency.max(1) {
+            conns.push(DbConn::get_one(rocket).await?);
+        }
+
+        Some(Scheduler {
+            config,
+            client,
+            conns,
+        })
+    }
+
+    pub async fn run(self) {
+        info!("Starting refresh scheduler with config {:?}", self.config);
+
+        loop {
+            match self.run_cycle().await {
+                Ok(0) => (),
+                Ok(refreshed_count) => info!("Refreshed {} users", refreshed_count),
+                Err(err) => error!("Error getting users to refresh: {:?}", err),
+            }
+
+            tokio::time::sleep(self.config.cycle_interval).await;
+        }
+    }
+
+    /// Refreshes up to `cycle_budget` users that are due, returning how many were refreshed
+    /// successfully.
+    async fn run_cycle(&self) -> Result<usize, diesel::result::Error> {
+        let now = Utc::now().naive_utc();
+        let limit = self.config.cycle_budget as i64;
+        let user_ids = self.conns[0]
+            .run(move |conn| db_util::get_due_user_ids(conn, now, limit))
+            .await?;
+        if user_ids.is_empty() {
+            return Ok(0);
+        }
+
+        // Split the users evenly between workers, each of which refreshes its share one at a time
+        let chunk_size = user_ids.len().div_ceil(self.conns.len());
+        let refreshed_counts = future::join_all(
+            self.conns
+                .iter()
+                .zip(user_ids.chunks(chunk_size))
+                .map(|(conn, user_ids)| self.refresh_users(conn, user_ids)),
+        )
+        .await;
+
+        Ok(refreshed_counts.into_iter().sum())
+    }
+
+    async fn refresh_users(&self, conn: &DbConn, user_ids: &[i64]) -> usize {
+        let policy = &self.config.refresh_policy;
+        let mut refreshed_count = 0;
+
+        for &user_id in user_ids {
+            let now = Utc::now().naive_utc();
+            let (retry_after, last_updated_at) =
+                match crate::update_user(conn, &self.client, policy, user_id).await {
+                    Ok(_) => {
+                        refreshed_count += 1;
+                        continue;
+                    }
+                    Err(UpdateUserError::NotFound) => {
+                        // Users that no longer exist upstream are treated as dormant
+                        warn!("User {} not found from Quaver API", user_id);
+                        (policy.max_interval, Some(now))
+                    }
+                    Err(err) => {
+                        error!("Error refreshing user {}: {:?}", user_id, err);
+                        (policy.min_interval, None)
+                    }
+                };
+
+            // Push back failed users so that they don't get retried every cycle
+            let next_update_at = now
+                + chrono::Duration::from_std(retry_after)
+                    .unwrap_or_else(|_| chrono::Duration::weeks(1));
+            if let Err(err) = conn
+                .run(move |conn| {
+                    if let Some(last_updated_at) = last_updated_at {
+                        db_util::set_last_updated_at(conn, user_id, last_updated_at)?;
+                    }
+                    db_util::set_next_update_at(conn, user_id, next_update_at)
+                })
+                .await
+            {
+                error!("Error rescheduling user {}: {:?}", user_id, err);
+            }
+        }
+
+        refreshed_count
+    }
+}
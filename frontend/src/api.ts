@@ -22,6 +22,7 @@ export interface StatsUpdate {
   global_rank: number;
   country_rank: number;
   multiplayer_win_rank: number;
+  last_checked_at: string;
 }
 
 export interface Map {
@@ -31,12 +32,19 @@ export interface Map {
   difficulty_name: string;
 }
 
+export interface Mods {
+  bits: number;
+  // Excludes rate mods; see `rate`
+  list: string[];
+  rate: number;
+}
+
 export interface Score {
   id: number;
   user_id: number;
   time: string;
   mode: number;
-  mods: number;
+  mods: Mods;
   mods_string: string;
   performance_rating: number;
   personal_best: boolean;
@@ -56,22 +64,78 @@ export interface Score {
   map_id: number;
 }
 
+export interface UserCandidate {
+  id: number;
+  username: string;
+  /**
+   * The current or previous username that matched the lookup
+   */
+  matched_username: string;
+}
+
+/**
+ * Thrown when a user lookup matches several users rather than exactly one
+ */
+export class AmbiguousUserError {
+  constructor(public candidates: UserCandidate[]) {}
+}
+
 const mapStatus = (res: Response) => {
   if (res.ok) {
     return res.json();
   } else if (res.status === 404) {
     return null;
+  } else if (res.status === 300) {
+    return res.json().then((body: { candidates: UserCandidate[] }) => {
+      throw new AmbiguousUserError(body.candidates);
+    });
   }
 
   throw res.status;
 };
 
+/**
+ * Coarser resolutions only include the last stats update recorded each hour, day or week
+ */
+export type StatsResolution = 'raw' | 'hourly' | 'daily' | 'weekly';
+
+interface StatsHistoryPage {
+  updates: StatsUpdate[];
+  /**
+   * Opaque position to pass as `cursor` to get the next page
+   */
+  next_cursor: string | null;
+}
+
+/**
+ * Fetches a user's full stats history, following pagination cursors until the last page
+ */
 export const getStatsHistory = async (
   _key: string,
   user: string,
-  mode: string
-): Promise<StatsUpdate[] | null> =>
-  fetch(`/api/user/${user}/${mode}/stats_history`).then(mapStatus);
+  mode: string,
+  resolution: StatsResolution = 'raw'
+): Promise<StatsUpdate[] | null> => {
+  const updates: StatsUpdate[] = [];
+  let cursor: string | null = null;
+  do {
+    const params = new URLSearchParams({ resolution });
+    if (cursor !== null) {
+      params.set('cursor', cursor);
+    }
+    const page: StatsHistoryPage | null = await fetch(
+      `/api/user/${user}/${mode}/stats_history?${params}`
+    ).then(mapStatus);
+    if (!page) {
+      return null;
+    }
+
+    updates.push(...page.updates);
+    cursor = page.next_cursor;
+  } while (cursor !== null);
+
+  return updates;
+};
 
 export const getHiscores = async (
   _key: string,
@@ -80,11 +144,38 @@ export const getHiscores = async (
 ): Promise<{ maps: { [id: number]: Map }; scores: Score[] }> =>
   fetch(`/api/user/${user}/${mode}/scores`).then(mapStatus);
 
+export interface UserSearchResult {
+  id: number;
+  username: string;
+  /**
+   * The current or previous username that matched the search
+   */
+  matched_username: string;
+  avatar_url: string;
+  country: string;
+  global_rank_4k: number | null;
+  global_rank_7k: number | null;
+  last_updated_at: string | null;
+  rank: number;
+}
+
+export const searchUsers = async (_key: string, query: string): Promise<UserSearchResult[]> =>
+  fetch(`/api/users/search?q=${encodeURIComponent(query)}`).then(mapStatus);
+
+export interface UserBadge {
+  badge_id: number;
+  name: string;
+  description: string | null;
+  first_seen_at: string;
+  last_seen_at: string;
+  removed_at: string | null;
+}
+
 export interface UpdateData {
-  stats_4k: StatsUpdate;
-  stats_7k: StatsUpdate;
+  stats: StatsUpdate[];
   maps: Map[];
   new_scores: Score[];
+  badge_changes: { gained: UserBadge[]; removed: UserBadge[] };
 }
 
 export const updateUser = (username: string): Promise<UpdateData> =>
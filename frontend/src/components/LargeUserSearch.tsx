@@ -2,6 +2,8 @@ import React, { useState } from 'react';
 import { Callout } from '@blueprintjs/core';
 import { useHistory } from 'react-router-dom';
 
+import UserSuggestions from './UserSuggestions';
+
 const styles: { [key: string]: React.CSSProperties } = {
   searchContainer: {
     marginLeft: '10vw',
@@ -27,11 +29,22 @@ const styles: { [key: string]: React.CSSProperties } = {
     border: '1px solid #777',
     outline: 0,
   },
+  suggestionsWrapper: {
+    position: 'relative',
+    maxWidth: '70vw',
+    width: '100%',
+  },
+  suggestions: {
+    top: -8,
+    left: 0,
+    right: 0,
+  },
 };
 
 const LargeUserSearch: React.FC = () => {
   const history = useHistory();
   const [searchContent, setSearchContent] = useState('');
+  const [focused, setFocused] = useState(false);
 
   return (
     <Callout style={styles.searchContainer}>
@@ -40,12 +53,23 @@ const LargeUserSearch: React.FC = () => {
         style={styles.searchBox}
         value={searchContent}
         onChange={(evt) => setSearchContent(evt.target.value)}
+        onFocus={() => setFocused(true)}
+        onBlur={() => setFocused(false)}
         onKeyPress={(evt) => {
           if (evt.key === 'Enter' && searchContent.length > 0) {
             history.push(`/user/${searchContent}`);
           }
         }}
       />
+      {focused ? (
+        <div style={styles.suggestionsWrapper}>
+          <UserSuggestions
+            query={searchContent}
+            onSelect={(username) => history.push(`/user/${username}`)}
+            style={styles.suggestions}
+          />
+        </div>
+      ) : null}
     </Callout>
   );
 };
@@ -82,7 +82,10 @@ const formatTimeDiffSeconds = (seconds: number) => {
 };
 
 const LastUpdateChanges: React.FC<{
-  newUpdate: null | { '4k': StatsUpdate; '7k': StatsUpdate } | { error: string };
+  newUpdate:
+    | null
+    | { '4k': StatsUpdate | undefined; '7k': StatsUpdate | undefined }
+    | { error: string };
   lastUpdate: StatsUpdate | null;
   mode: Mode;
 }> = ({ newUpdate, lastUpdate, mode }) => {
@@ -95,6 +98,10 @@ const LastUpdateChanges: React.FC<{
   }
 
   const newModeUpdate = mode === Mode.K4 ? newUpdate['4k'] : newUpdate['7k'];
+  if (!newModeUpdate) {
+    return <>No stats were returned for this mode in the latest update.</>;
+  }
+
   const timeDiffSeconds = dayjs(lastUpdate.recorded_at).diff(
     dayjs(newModeUpdate.recorded_at),
     'second'
@@ -1,5 +1,7 @@
 import React, { useState } from 'react';
 
+import UserSuggestions from './UserSuggestions';
+
 const styles: { [key: string]: React.CSSProperties } = {
   globalSearchWrapper: {
     position: 'absolute',
@@ -9,10 +11,23 @@ const styles: { [key: string]: React.CSSProperties } = {
   globalSearch: {
     background: '#283030',
   },
+  suggestions: {
+    top: 34,
+    right: 0,
+    minWidth: 280,
+  },
 };
 
 const UserSearch: React.FC<{ onSubmit: (value: string) => void }> = ({ onSubmit }) => {
   const [userSearchValue, setUserSearchValue] = useState('');
+  const [focused, setFocused] = useState(false);
+
+  const submit = (username: string) => {
+    if (onSubmit) {
+      onSubmit(username);
+    }
+    setUserSearchValue('');
+  };
 
   return (
     <div style={styles.globalSearchWrapper} className='bp3-input-group'>
@@ -25,17 +40,26 @@ const UserSearch: React.FC<{ onSubmit: (value: string) => void }> = ({ onSubmit
         type='search'
         placeholder='Search User'
         size={14}
+        onFocus={() => setFocused(true)}
+        onBlur={() => setFocused(false)}
         onKeyDown={(e) => {
           if (e.key === 'Enter') {
-            if (onSubmit) {
-              onSubmit(userSearchValue);
-            }
+            submit(userSearchValue);
             (e.target as any).blur();
-            setUserSearchValue('');
           }
         }}
         dir='auto'
       />
+      {focused ? (
+        <UserSuggestions
+          query={userSearchValue}
+          onSelect={(username) => {
+            submit(username);
+            (document.activeElement as HTMLElement | null)?.blur();
+          }}
+          style={styles.suggestions}
+        />
+      ) : null}
     </div>
   );
 };
@@ -0,0 +1,97 @@
+import React, { useEffect, useState } from 'react';
+import { useQuery } from 'react-query';
+import { Menu, MenuItem } from '@blueprintjs/core';
+
+import { searchUsers, UserSearchResult } from '../api';
+
+const styles: { [key: string]: React.CSSProperties } = {
+  menu: {
+    position: 'absolute',
+    zIndex: 20,
+    textAlign: 'left',
+    maxHeight: 360,
+    overflowY: 'auto',
+  },
+  avatar: {
+    width: 20,
+    height: 20,
+    borderRadius: 3,
+  },
+  formerly: {
+    color: '#999',
+    fontSize: 12,
+    marginLeft: 6,
+  },
+};
+
+/**
+ * Returns `value` once it has stopped changing for `delayMs`
+ */
+const useDebounced = <T,>(value: T, delayMs: number): T => {
+  const [debounced, setDebounced] = useState(value);
+
+  useEffect(() => {
+    const timeout = setTimeout(() => setDebounced(value), delayMs);
+    return () => clearTimeout(timeout);
+  }, [value, delayMs]);
+
+  return debounced;
+};
+
+const formatRank = (rank: number | null) => (rank === null ? '-' : `#${rank}`);
+
+const renderLabel = (user: UserSearchResult) =>
+  `4K ${formatRank(user.global_rank_4k)} / 7K ${formatRank(user.global_rank_7k)}`;
+
+interface UserSuggestionsProps {
+  query: string;
+  onSelect: (username: string) => void;
+  style?: React.CSSProperties;
+}
+
+/**
+ * Dropdown of tracked users matching `query`, for use below a user search box
+ */
+const UserSuggestions: React.FC<UserSuggestionsProps> = ({ query, onSelect, style }) => {
+  const debouncedQuery = useDebounced(query.trim(), 200);
+  const { data: suggestions } = useQuery({
+    queryKey: ['userSearch', debouncedQuery],
+    queryFn: searchUsers,
+    config: {
+      enabled: debouncedQuery.length > 0,
+      refetchOnWindowFocus: false,
+      staleTime: 60 * 1000,
+    },
+  });
+
+  if (!query.trim() || !suggestions || suggestions.length === 0) {
+    return null;
+  }
+
+  return (
+    <Menu style={{ ...styles.menu, ...(style || {}) }}>
+      {suggestions.map((user) => (
+        <MenuItem
+          key={user.id}
+          icon={<img style={styles.avatar} src={user.avatar_url} alt='' />}
+          text={
+            <>
+              {user.username}
+              {user.matched_username !== user.username ? (
+                <span style={styles.formerly}>formerly {user.matched_username}</span>
+              ) : null}
+            </>
+          }
+          label={renderLabel(user)}
+          // Select on mouse down so that the search box losing focus doesn't hide this first
+          onMouseDown={(evt) => {
+            evt.preventDefault();
+            onSelect(user.username);
+          }}
+        />
+      ))}
+    </Menu>
+  );
+};
+
+export default UserSuggestions;
@@ -6,7 +6,15 @@ import { Button, ButtonGroup } from '@blueprintjs/core';
 import { Select, IItemRendererProps, IItemListRendererProps } from '@blueprintjs/select';
 import { PromiseResolveType, Without } from 'ameo-utils';
 
-import { getHiscores, getStatsHistory, StatsUpdate, updateUser, Map, Score } from '../api';
+import {
+  getHiscores,
+  getStatsHistory,
+  StatsUpdate,
+  updateUser,
+  Map,
+  Score,
+  AmbiguousUserError,
+} from '../api';
 import { TrendChart, getSeriesDefaults, ScatterPlot } from '../components/Charts';
 import * as colors from '../styles/colors';
 import LastUpdateChanges from '../components/LastUpdateChanges';
@@ -172,12 +180,12 @@ const useSeries = ({
   playcountType: PlaycountType;
   accType: AccType;
   statsUpdates: StatsUpdate[] | null | undefined;
-  lastUpdate: {
-    '4k': StatsUpdate;
-    '7k': StatsUpdate;
-  } | null;
+  lastUpdate: Pick<UpdateResult, '4k' | '7k'> | null;
   mode: '4k' | '7k';
 }) => {
+  // No latest point is appended when the update didn't include this mode
+  const latestUpdate = lastUpdate ? lastUpdate[mode] : undefined;
+
   const rankSeries = useMemo(() => {
     if (!statsUpdates) {
       return null;
@@ -210,17 +218,17 @@ const useSeries = ({
       },
     ];
 
-    if (lastUpdate) {
-      ret[0].data.push([new Date(lastUpdate[mode].recorded_at + 'Z'), lastUpdate[mode][rankType]]);
+    if (latestUpdate) {
+      ret[0].data.push([new Date(latestUpdate.recorded_at + 'Z'), latestUpdate[rankType]]);
 
       ret[1].data.push([
-        new Date(lastUpdate[mode].recorded_at + 'Z'),
-        lastUpdate[mode].overall_performance_rating,
+        new Date(latestUpdate.recorded_at + 'Z'),
+        latestUpdate.overall_performance_rating,
       ]);
     }
 
     return ret;
-  }, [statsUpdates, rankType, lastUpdate, mode]);
+  }, [statsUpdates, rankType, latestUpdate]);
   const scoreSeries = useMemo(() => {
     if (!statsUpdates) {
       return null;
@@ -239,12 +247,12 @@ const useSeries = ({
       itemStyle: { color: colors.emphasis, borderColor: '#fff' },
     };
 
-    if (lastUpdate) {
-      ret.data.push([new Date(lastUpdate[mode].recorded_at + 'Z'), lastUpdate[mode][scoreType]]);
+    if (latestUpdate) {
+      ret.data.push([new Date(latestUpdate.recorded_at + 'Z'), latestUpdate[scoreType]]);
     }
 
     return [ret];
-  }, [statsUpdates, scoreType, lastUpdate, mode]);
+  }, [statsUpdates, scoreType, latestUpdate]);
   const playcountSeries = useMemo(() => {
     if (!statsUpdates) {
       return null;
@@ -268,15 +276,12 @@ const useSeries = ({
       itemStyle: { color: colors.emphasis, borderColor: '#fff' },
     };
 
-    if (lastUpdate) {
-      ret.data.push([
-        new Date(lastUpdate[mode].recorded_at + 'Z'),
-        lastUpdate[mode][playcountType],
-      ]);
+    if (latestUpdate) {
+      ret.data.push([new Date(latestUpdate.recorded_at + 'Z'), latestUpdate[playcountType]]);
     }
 
     return [ret];
-  }, [statsUpdates, playcountType, lastUpdate, mode]);
+  }, [statsUpdates, playcountType, latestUpdate]);
   const accSeries = useMemo(() => {
     if (!statsUpdates) {
       return null;
@@ -293,11 +298,8 @@ const useSeries = ({
         itemStyle: { color: colors.emphasis, borderColor: '#fff' },
       };
 
-      if (lastUpdate) {
-        ret.data.push([
-          new Date(lastUpdate[mode].recorded_at + 'Z'),
-          lastUpdate[mode].overall_accuracy,
-        ]);
+      if (latestUpdate) {
+        ret.data.push([new Date(latestUpdate.recorded_at + 'Z'), latestUpdate.overall_accuracy]);
       }
 
       return [ret];
@@ -331,20 +333,20 @@ const useSeries = ({
       }
     );
 
-    if (lastUpdate) {
-      const date = new Date(lastUpdate[mode].recorded_at + 'Z');
+    if (latestUpdate) {
+      const date = new Date(latestUpdate.recorded_at + 'Z');
       const totalHits =
-        lastUpdate[mode].total_marv +
-        lastUpdate[mode].total_great +
-        lastUpdate[mode].total_good +
-        lastUpdate[mode].total_okay +
-        lastUpdate[mode].total_miss;
-
-      marv.push([date, lastUpdate[mode].total_marv / totalHits]);
-      great.push([date, lastUpdate[mode].total_great / totalHits]);
-      good.push([date, lastUpdate[mode].total_good / totalHits]);
-      okay.push([date, lastUpdate[mode].total_okay / totalHits]);
-      miss.push([date, lastUpdate[mode].total_miss / totalHits]);
+        latestUpdate.total_marv +
+        latestUpdate.total_great +
+        latestUpdate.total_good +
+        latestUpdate.total_okay +
+        latestUpdate.total_miss;
+
+      marv.push([date, latestUpdate.total_marv / totalHits]);
+      great.push([date, latestUpdate.total_great / totalHits]);
+      good.push([date, latestUpdate.total_good / totalHits]);
+      okay.push([date, latestUpdate.total_okay / totalHits]);
+      miss.push([date, latestUpdate.total_miss / totalHits]);
     }
 
     const seriesDefaults = {
@@ -405,9 +407,20 @@ const getModeID = (mode: '4k' | '7k') => {
   return 2;
 };
 
+/**
+ * The result of the update triggered when the page loads.  Upstream can leave a mode out of the
+ * update, so the per-mode stats may be missing.
+ */
+type UpdateResult = {
+  '4k': StatsUpdate | undefined;
+  '7k': StatsUpdate | undefined;
+  newScores: Score[];
+  maps: Map[];
+};
+
 const buildHiscoresSeries = (
   hiscores: PromiseResolveType<ReturnType<typeof getHiscores>>,
-  lastUpdate: { '4k': StatsUpdate; '7k': StatsUpdate; newScores: Score[]; maps: Map[] } | null,
+  lastUpdate: UpdateResult | null,
   mode: '4k' | '7k'
 ) => {
   if (!hiscores) {
@@ -470,27 +483,30 @@ const UserInfo: React.FC = () => {
   const match = useRouteMatch<{ username: string; mode: string }>();
   const { username, mode: rawMode } = match.params;
   const mode = Option.of(rawMode).getOrElse(Mode.K4);
-  const { data: statsUpdates } = useQuery({
-    queryKey: ['statsHistory', username, mode],
+  const { data: statsUpdates, error: statsError } = useQuery({
+    // Hourly snapshots are plenty for the charts and much smaller for long-tracked users
+    queryKey: ['statsHistory', username, mode, 'hourly'],
     queryFn: getStatsHistory,
-    config: { refetchOnWindowFocus: false, staleTime: 40 * 1000 },
+    config: {
+      refetchOnWindowFocus: false,
+      staleTime: 40 * 1000,
+      // Asking again won't make an ambiguous username any less ambiguous
+      retry: (failureCount: number, err: unknown) =>
+        !(err instanceof AmbiguousUserError) && failureCount < 3,
+    },
   });
   const { data: hiscores } = useQuery({
     queryKey: ['scoresHistory', username, mode],
     queryFn: getHiscores,
     config: { refetchOnWindowFocus: false, staleTime: 40 * 1000 },
   });
-  const [lastUpdate, setLastUpdate] = useState<
-    | null
-    | { '4k': StatsUpdate; '7k': StatsUpdate; newScores: Score[]; maps: Map[] }
-    | { error: string }
-  >(null);
+  const [lastUpdate, setLastUpdate] = useState<null | UpdateResult | { error: string }>(null);
   const hiscoresSeries = useMemo(
     () =>
       hiscores
         ? buildHiscoresSeries(
             hiscores,
-            lastUpdate && '4k' in lastUpdate ? lastUpdate : null,
+            lastUpdate && 'newScores' in lastUpdate ? lastUpdate : null,
             mode === '4k' || mode === '7k' ? mode : '4k'
           )
         : null,
@@ -506,7 +522,7 @@ const UserInfo: React.FC = () => {
     scoreType,
     playcountType,
     statsUpdates,
-    lastUpdate: lastUpdate && '4k' in lastUpdate ? lastUpdate : null,
+    lastUpdate: lastUpdate && 'newScores' in lastUpdate ? lastUpdate : null,
     mode: mode === '4k' || mode === '7k' ? mode : '4k',
     accType,
   });
@@ -516,10 +532,22 @@ const UserInfo: React.FC = () => {
 
     // Trigger an update and get the most recent stats for the user and display
     updateUser(username)
-      .then(({ stats_4k, stats_7k, new_scores, maps }) =>
-        setLastUpdate({ '4k': stats_4k, '7k': stats_7k, newScores: new_scores, maps })
-      )
-      .catch((resCode: number) => {
+      .then(({ stats, new_scores, maps }) => {
+        const statsForMode = (mode: '4k' | '7k') =>
+          stats.find(update => update.mode === getModeID(mode));
+        setLastUpdate({
+          '4k': statsForMode('4k'),
+          '7k': statsForMode('7k'),
+          newScores: new_scores,
+          maps,
+        });
+      })
+      .catch((resCode: number | AmbiguousUserError) => {
+        if (resCode instanceof AmbiguousUserError) {
+          setLastUpdate({ error: 'Multiple users match this name' });
+          return;
+        }
+
         console.warn(`Code ${resCode} when updating user ${username}`);
         switch (resCode) {
           case 404: {
@@ -548,6 +576,28 @@ const UserInfo: React.FC = () => {
     return null;
   }
 
+  if (statsError instanceof AmbiguousUserError) {
+    return (
+      <div className='user-info' style={{ ...styles.root, ...styles.rootNotFound }}>
+        <h1>Did you mean...</h1>
+        <ButtonGroup vertical>
+          {statsError.candidates.map((candidate) => (
+            <Button
+              key={candidate.id}
+              onClick={() => history.push(`/user/${candidate.username}/${mode}`)}
+            >
+              {candidate.username}
+              {candidate.matched_username !== candidate.username
+                ? ` (formerly ${candidate.matched_username})`
+                : null}
+            </Button>
+          ))}
+        </ButtonGroup>
+        <LargeUserSearch />
+      </div>
+    );
+  }
+
   if (statsUpdates === null) {
     return (
       <div className='user-info' style={{ ...styles.root, ...styles.rootNotFound }}>
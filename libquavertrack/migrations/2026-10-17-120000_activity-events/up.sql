@@ -0,0 +1,14 @@
+-- `id` is the upstream activity feed id, so re-fetching the feed never creates duplicates.
+-- `map_id` is set for entries about a real map, which won't necessarily be in `maps` since
+-- upstream often only sends the map's id and name.  Entries like achievements come with a
+-- placeholder map, so only their name is kept.
+CREATE TABLE activity_events (
+  id BIGINT PRIMARY KEY,
+  user_id BIGINT NOT NULL,
+  activity_type SMALLINT NOT NULL,
+  timestamp TIMESTAMP NOT NULL,
+  map_id BIGINT,
+  map_name TEXT
+);
+
+CREATE INDEX activity_events_user_id_idx ON activity_events (user_id, id DESC);
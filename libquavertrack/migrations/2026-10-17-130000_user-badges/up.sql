@@ -0,0 +1,12 @@
+-- `removed_at` is set when a badge that a user had is no longer returned by upstream, and cleared
+-- again if it comes back.
+CREATE TABLE user_badges (
+  user_id BIGINT NOT NULL,
+  badge_id BIGINT NOT NULL,
+  name TEXT NOT NULL,
+  description TEXT,
+  first_seen_at TIMESTAMP NOT NULL,
+  last_seen_at TIMESTAMP NOT NULL,
+  removed_at TIMESTAMP,
+  PRIMARY KEY (user_id, badge_id)
+);
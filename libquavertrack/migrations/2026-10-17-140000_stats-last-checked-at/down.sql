@@ -0,0 +1,2 @@
+DROP INDEX stats_updates_user_id_mode_recorded_at_idx;
+ALTER TABLE stats_updates DROP COLUMN last_checked_at;
@@ -0,0 +1,8 @@
+-- Snapshots are only inserted when a user's stats change.  `last_checked_at` is bumped every
+-- time upstream returns stats identical to the latest snapshot instead.
+ALTER TABLE stats_updates ADD COLUMN last_checked_at TIMESTAMP DEFAULT current_timestamp;
+UPDATE stats_updates SET last_checked_at = recorded_at;
+ALTER TABLE stats_updates ALTER COLUMN last_checked_at SET NOT NULL;
+
+CREATE INDEX stats_updates_user_id_mode_recorded_at_idx
+  ON stats_updates (user_id, mode, recorded_at DESC);
@@ -0,0 +1,2 @@
+DROP INDEX users_next_update_at_idx;
+ALTER TABLE users DROP COLUMN next_update_at;
@@ -0,0 +1,5 @@
+-- When the background scheduler should next refresh each user.  NULL means as soon as possible.
+ALTER TABLE users ADD COLUMN next_update_at TIMESTAMP;
+UPDATE users SET next_update_at = last_updated_at;
+
+CREATE INDEX users_next_update_at_idx ON users (next_update_at NULLS FIRST);
@@ -0,0 +1,9 @@
+DROP TABLE user_profile_history;
+
+ALTER TABLE users
+  DROP COLUMN privileges,
+  DROP COLUMN usergroups,
+  DROP COLUMN mute_endtime,
+  DROP COLUMN latest_activity,
+  DROP COLUMN online,
+  DROP COLUMN userpage;
@@ -0,0 +1,28 @@
+-- Profile fields that upstream returns but which weren't stored previously
+ALTER TABLE users
+  ADD COLUMN privileges BIGINT,
+  ADD COLUMN usergroups BIGINT,
+  ADD COLUMN mute_endtime TIMESTAMP,
+  ADD COLUMN latest_activity TIMESTAMP,
+  ADD COLUMN online BOOLEAN,
+  ADD COLUMN userpage TEXT;
+
+-- A row is recorded every time any of a user's username, country, avatar or usergroups changes so
+-- that users can still be found by the names they used to have.
+CREATE TABLE user_profile_history (
+  id SERIAL PRIMARY KEY,
+  user_id BIGINT NOT NULL,
+  username TEXT NOT NULL,
+  country VARCHAR(4) NOT NULL,
+  avatar_url TEXT NOT NULL,
+  usergroups BIGINT,
+  recorded_at TIMESTAMP NOT NULL
+);
+
+INSERT INTO user_profile_history (user_id, username, country, avatar_url, recorded_at)
+  SELECT id, username, country, avatar_url, COALESCE(last_updated_at, current_timestamp)
+  FROM users;
+
+CREATE INDEX user_profile_history_user_id_recorded_at_idx
+  ON user_profile_history (user_id, recorded_at);
+CREATE INDEX user_profile_history_username_idx ON user_profile_history (username);
@@ -0,0 +1,5 @@
+DROP INDEX user_profile_history_username_trgm_idx;
+DROP INDEX user_profile_history_unaccent_username_idx;
+DROP FUNCTION immutable_unaccent(text);
+DROP EXTENSION unaccent;
+DROP EXTENSION pg_trgm;
@@ -0,0 +1,15 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+CREATE EXTENSION IF NOT EXISTS unaccent;
+
+-- `unaccent` isn't marked immutable since its behavior depends on the dictionary it's given, so it
+-- can't be used in indexes directly.  Pinning the dictionary makes it safe to.
+CREATE FUNCTION immutable_unaccent(text) RETURNS text AS $$
+  SELECT public.unaccent('public.unaccent', $1)
+$$ LANGUAGE sql IMMUTABLE PARALLEL SAFE STRICT;
+
+-- Usernames are stored lowercased, so these allow case- and accent-insensitive exact and trigram
+-- matching against every name each user has had, including their current one.
+CREATE INDEX user_profile_history_unaccent_username_idx
+  ON user_profile_history (immutable_unaccent(username));
+CREATE INDEX user_profile_history_username_trgm_idx
+  ON user_profile_history USING GIN (immutable_unaccent(username) gin_trgm_ops);
@@ -0,0 +1,2 @@
+INSERT INTO stats_updates SELECT * FROM stats_rollups;
+DROP TABLE stats_rollups;
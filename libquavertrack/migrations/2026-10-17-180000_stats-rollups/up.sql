@@ -0,0 +1,7 @@
+-- Old snapshots that the retention job has rolled up.  Rows keep the id they had in
+-- `stats_updates` and have exactly the same columns so that the two can be queried together.
+CREATE TABLE stats_rollups (LIKE stats_updates INCLUDING CONSTRAINTS);
+ALTER TABLE stats_rollups ADD PRIMARY KEY (id);
+
+CREATE INDEX stats_rollups_user_id_mode_recorded_at_idx
+  ON stats_rollups (user_id, mode, recorded_at DESC);
@@ -0,0 +1,127 @@
+use serde::{de::DeserializeOwned, Deserialize};
+
+use super::{APIError, QuaverResponse};
+
+/// How many bytes of the response body to include on either side of the point where decoding
+/// failed
+const EXCERPT_CONTEXT: usize = 100;
+
+/// Shape of the bodies the Quaver API returns alongside errors such as 404s
+#[derive(Deserialize)]
+struct ErrorBody {
+    status: u32,
+    error: String,
+}
+
+/// A decoded response along with the paths of any fields in it that the model didn't know about
+pub struct Decoded<T> {
+    pub response: QuaverResponse<T>,
+    pub unknown_fields: Vec<String>,
+}
+
+/// Decodes a response body from `endpoint`.  Unlike plain `serde_json`, failures report the JSON
+/// path of the value that couldn't be decoded along with an excerpt of the body around it so that
+/// upstream schema changes are easy to track down.
+pub fn decode_response<T: DeserializeOwned>(
+    endpoint: &str,
+    body: &str,
+) -> Result<Decoded<T>, APIError> {
+    if let Ok(ErrorBody { status, error }) = serde_json::from_str::<ErrorBody>(body) {
+        return Ok(Decoded {
+            response: QuaverResponse::Error { status, error },
+            unknown_fields: Vec::new(),
+        });
+    }
+
+    let mut unknown_fields = Vec::new();
+    let mut record_unknown_field =
+        |path: serde_ignored::Path| unknown_fields.push(path.to_string());
+    let mut deserializer = serde_json::Deserializer::from_str(body);
+    let res = serde_path_to_error::deserialize(serde_ignored::Deserializer::new(
+        &mut deserializer,
+        &mut record_unknown_field,
+    ))
+    .map_err(|err| {
+        let path = err.path().to_string();
+        (path, err.into_inner())
+    })
+    .and_then(|val| {
+        deserializer
+            .end()
+            .map(|_| val)
+            .map_err(|err| (String::from("."), err))
+    });
+
+    match res {
+        Ok(val) => Ok(Decoded {
+            response: QuaverResponse::Success(val),
+            unknown_fields,
+        }),
+        Err((path, source)) => Err(APIError::Decode {
+            endpoint: endpoint.to_owned(),
+            path,
+            body_excerpt: body_excerpt(body, &source),
+            source,
+        }),
+    }
+}
+
+/// Returns the part of `body` surrounding the position that `err` occurred at
+fn body_excerpt(body: &str, err: &serde_json::Error) -> String {
+    let offset = body
+        .split('\n')
+        .take(err.line().saturating_sub(1))
+        .map(|line| line.len() + 1)
+        .sum::<usize>()
+        + err.column().saturating_sub(1);
+    let offset = offset.min(body.len());
+
+    let mut start = offset.saturating_sub(EXCERPT_CONTEXT);
+    while !body.is_char_boundary(start) {
+        start -= 1;
+    }
+    let mut end = (offset + EXCERPT_CONTEXT).min(body.len());
+    while !body.is_char_boundary(end) {
+        end += 1;
+    }
+
+    body[start..end].to_owned()
+}
+
+#[test]
+fn decode_failures_are_located() {
+    use crate::db_util::models::APIScoresResponse;
+
+    let score = r#"{"id":1,"time":"2021-04-13T12:00:00.000Z","mode":1,"mods":0,"mods_string":"None","performance_rating":10.5,"personal_best":true,"total_score":900000,"accuracy":"very high","grade":"A","max_combo":100,"count_marv":1,"count_perf":0,"count_great":0,"count_good":0,"count_okay":0,"count_miss":0,"scroll_speed":20,"ratio":1.0,"map":{"id":1,"mapset_id":1,"md5":"x","artist":"a","title":"t","difficulty_name":"d","creator_id":1,"creator_username":"c","ranked_status":2}}"#;
+    let body = format!(r#"{{"status":200,"scores":[{}]}}"#, score);
+
+    match decode_response::<APIScoresResponse>("/v1/users/scores/best?id=1&mode=1", &body) {
+        Err(APIError::Decode {
+            endpoint,
+            path,
+            body_excerpt,
+            ..
+        }) => {
+            assert_eq!(endpoint, "/v1/users/scores/best?id=1&mode=1");
+            assert_eq!(path, "scores[0].accuracy");
+            assert!(body_excerpt.contains(r#""accuracy":"very high""#));
+        },
+        Err(err) => panic!("Unexpected error: {}", err),
+        Ok(_) => panic!("Invalid accuracy was accepted"),
+    }
+
+    let body = body.replace(r#""very high""#, r#"98.5,"clan":"QT""#);
+    let decoded = decode_response::<APIScoresResponse>("/v1/users/scores/best", &body).unwrap();
+    assert_eq!(decoded.unknown_fields, vec!["scores.0.clan"]);
+    match decoded.response {
+        QuaverResponse::Success(res) => assert_eq!(res.scores.len(), 1),
+        QuaverResponse::Error { .. } => panic!("Decoded a score as an error"),
+    }
+
+    let decoded = decode_response::<APIScoresResponse>(
+        "/v1/users/scores/best",
+        r#"{"status":404,"error":"Not Found"}"#,
+    )
+    .unwrap();
+    assert!(matches!(decoded.response.success(), Ok(None)));
+}
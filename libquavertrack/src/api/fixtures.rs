@@ -0,0 +1,126 @@
+use std::path::{Component, Path, PathBuf};
+
+use serde::Deserialize;
+
+/// Lets a [`QuaverClient`](super::QuaverClient) capture real API responses as fixtures or serve
+/// previously captured ones instead of hitting the network.  Fixtures are the raw response
+/// bodies, stored at the path given by [`fixture_path`].
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(tag = "mode", content = "dir", rename_all = "lowercase")]
+pub enum FixtureMode {
+    #[default]
+    Off,
+    /// Write every response body received from the API into this directory
+    Record(PathBuf),
+    /// Read responses from this directory and never make any network requests
+    Replay(PathBuf),
+}
+
+/// Returns where the response for `request_path` is stored within `fixtures_dir`.  The query
+/// string becomes the file name, so `/v1/users/scores/best?id=1&mode=1` is stored at
+/// `v1/users/scores/best/id=1&mode=1.json` and `/v1/users/full/1/` at `v1/users/full/1.json`.
+/// Returns `None` if the path has `..` or other segments that could point outside of
+/// `fixtures_dir`.
+pub fn fixture_path(fixtures_dir: &Path, request_path: &str) -> Option<PathBuf> {
+    let (path, query) = match request_path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (request_path, None),
+    };
+
+    let mut relative_path = path.trim_matches('/').to_owned();
+    if let Some(query) = query {
+        relative_path.push('/');
+        relative_path.push_str(query);
+    }
+    relative_path.push_str(".json");
+
+    let relative_path = Path::new(&relative_path);
+    if !relative_path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+    Some(fixtures_dir.join(relative_path))
+}
+
+#[test]
+fn fixture_paths() {
+    let dir = Path::new("fixtures");
+
+    assert_eq!(
+        fixture_path(dir, "/v1/users/full/19250/").unwrap(),
+        Path::new("fixtures/v1/users/full/19250.json")
+    );
+    assert_eq!(
+        fixture_path(dir, "/v1/users/scores/best?id=19250&mode=1").unwrap(),
+        Path::new("fixtures/v1/users/scores/best/id=19250&mode=1.json")
+    );
+    assert_eq!(
+        fixture_path(dir, "/v1/users?id=19250").unwrap(),
+        Path::new("fixtures/v1/users/id=19250.json")
+    );
+
+    // Nothing outside of the fixtures directory can be read or written
+    assert_eq!(fixture_path(dir, "/v1/users/search/../../../x"), None);
+    assert_eq!(fixture_path(dir, "/v1/users?id=1/../../../x"), None);
+    assert_eq!(
+        fixture_path(dir, "/v1/users/search/..%2F..%2Fx").unwrap(),
+        Path::new("fixtures/v1/users/search/..%2F..%2Fx.json")
+    );
+}
+
+/// Deserializes every fixture stored in the repo into the models the client decodes it as, so that
+/// upstream schema changes picked up by re-recording them show up here.
+#[test]
+fn all_fixtures_deserialize() {
+    use serde::de::DeserializeOwned;
+
+    use crate::db_util::models::{
+        APIGetUserStatsResponse, APIGetUsersResponse, APIScoresResponse, APISearchUsersResponse,
+    };
+
+    fn collect_fixtures(dir: &Path, out: &mut Vec<PathBuf>) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                collect_fixtures(&path, out);
+            } else if path.extension() == Some("json".as_ref()) {
+                out.push(path);
+            }
+        }
+    }
+
+    fn check<T: DeserializeOwned>(path: &Path, body: &str) {
+        // Error responses such as 404s are recorded too; those don't use the endpoint's model
+        let value: serde_json::Value = serde_json::from_str(body)
+            .unwrap_or_else(|err| panic!("{} isn't valid JSON: {}", path.display(), err));
+        if value["status"] != 200 {
+            assert!(value["error"].is_string(), "{}", path.display());
+            return;
+        }
+
+        if let Err(err) = serde_json::from_str::<T>(body) {
+            panic!("Failed to deserialize {}: {}", path.display(), err);
+        }
+    }
+
+    let fixtures_dir = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures"));
+    let mut fixtures = Vec::new();
+    collect_fixtures(fixtures_dir, &mut fixtures);
+    assert!(!fixtures.is_empty());
+
+    for path in fixtures {
+        let body = std::fs::read_to_string(&path).unwrap();
+        let endpoint = path.strip_prefix(fixtures_dir).unwrap().parent().unwrap();
+        match endpoint.to_str().unwrap() {
+            "v1/users/full" => check::<APIGetUserStatsResponse>(&path, &body),
+            "v1/users/scores/best" | "v1/users/scores/recent" => {
+                check::<APIScoresResponse>(&path, &body)
+            },
+            "v1/users/search" => check::<APISearchUsersResponse>(&path, &body),
+            "v1/users" => check::<APIGetUsersResponse>(&path, &body),
+            other => panic!("Don't know which endpoint {} is from", other),
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use super::fixtures::fixture_path;
+
+#[derive(Clone, Debug)]
+pub struct MockResponse {
+    pub status: u16,
+    pub body: String,
+}
+
+#[derive(Default)]
+struct MockState {
+    fixtures_dir: PathBuf,
+    /// Responses to send instead of the fixture for a path, consumed in order
+    queued: HashMap<String, VecDeque<MockResponse>>,
+    requests: Vec<String>,
+}
+
+impl MockState {
+    fn respond(&mut self, path: &str) -> MockResponse {
+        self.requests.push(path.to_owned());
+
+        if let Some(res) = self.queued.get_mut(path).and_then(VecDeque::pop_front) {
+            return res;
+        }
+
+        let body = fixture_path(&self.fixtures_dir, path)
+            .and_then(|fixture_path| std::fs::read_to_string(fixture_path).ok());
+        match body {
+            Some(body) => MockResponse { status: 200, body },
+            None => MockResponse {
+                status: 404,
+                body: r#"{"status":404,"error":"Not Found"}"#.to_owned(),
+            },
+        }
+    }
+}
+
+/// In-process stand-in for the Quaver API.  Serves responses recorded in a fixtures directory
+/// (see [`fixture_path`]) and responds with 404 for anything that doesn't have a fixture.  Tests
+/// can queue up other responses for a path to simulate upstream errors.
+pub struct MockQuaverAPI {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    server: JoinHandle<()>,
+}
+
+impl MockQuaverAPI {
+    pub async fn start(fixtures_dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(MockState {
+            fixtures_dir: fixtures_dir.into(),
+            ..MockState::default()
+        }));
+
+        let server_state = Arc::clone(&state);
+        let server = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let state = Arc::clone(&server_state);
+                tokio::spawn(async move {
+                    if let Err(err) = handle_connection(stream, state).await {
+                        warn!("Error handling mock Quaver API request: {:?}", err);
+                    }
+                });
+            }
+        });
+
+        Ok(MockQuaverAPI {
+            addr,
+            state,
+            server,
+        })
+    }
+
+    /// Base URL to configure the `QuaverClient` under test with
+    pub fn base_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Responds to the next request for `path` (including the query string) with `body` instead
+    /// of the fixture.  Responses queued for the same path are sent in order.
+    pub fn enqueue(&self, path: &str, status: u16, body: impl Into<String>) {
+        self.state
+            .lock()
+            .unwrap()
+            .queued
+            .entry(path.to_owned())
+            .or_default()
+            .push_back(MockResponse {
+                status,
+                body: body.into(),
+            });
+    }
+
+    /// Paths of every request received so far, in order
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+impl Drop for MockQuaverAPI {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, state: Arc<Mutex<MockState>>) -> io::Result<()> {
+    // We only ever get bodiless GET requests, so the request is over once the headers are
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    }
+
+    let head = String::from_utf8_lossy(&buf);
+    let path = head.split_whitespace().nth(1).unwrap_or("/");
+    let res = state.lock().unwrap().respond(path);
+
+    let reason = match res.status {
+        200 => "OK",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: \
+         close\r\n\r\n",
+        res.status,
+        reason,
+        res.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(res.body.as_bytes()).await?;
+    stream.shutdown().await
+}
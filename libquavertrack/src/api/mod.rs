@@ -1,21 +1,84 @@
-use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::de::DeserializeOwned;
 use thiserror::Error;
 
+pub mod decode;
+pub mod fixtures;
+#[cfg(feature = "mock")]
+pub mod mock;
+pub mod rate_limit;
+pub mod retry;
+
+use self::{
+    decode::{decode_response, Decoded},
+    fixtures::{fixture_path, FixtureMode},
+    rate_limit::{RateLimitConfig, RateLimiter},
+    retry::{AttemptCounters, AttemptStats, RetryConfig},
+};
+
 use crate::db_util::models::{
     APIGetUserStatsResponse, APIGetUsersResponse, APIScore, APIScoresResponse,
-    APISearchUsersResponse, APIStatsUser, APIUser,
+    APISearchUsersResponse, APIStatsUser, APIUser, GameMode, UserCandidate,
 };
 
-const BASE_URL: &str = "https://api.quavergame.com";
+pub const DEFAULT_BASE_URL: &str = "https://api.quavergame.com";
+
+/// Characters that are escaped when putting user input into a path segment: everything except
+/// the ones that are never special in URLs
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Settings used to build a [`QuaverClient`].  The defaults point at the public Quaver API.
+#[derive(Clone, Debug)]
+pub struct QuaverClientConfig {
+    pub base_url: String,
+    /// Total time allowed for a single request, including reading the response body
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    pub user_agent: String,
+    pub retry: RetryConfig,
+    pub rate_limit: RateLimitConfig,
+    pub fixtures: FixtureMode,
+}
+
+impl Default for QuaverClientConfig {
+    fn default() -> Self {
+        QuaverClientConfig {
+            base_url: DEFAULT_BASE_URL.to_owned(),
+            timeout: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(10),
+            user_agent: concat!("quavertrack/", env!("CARGO_PKG_VERSION")).to_owned(),
+            retry: RetryConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            fixtures: FixtureMode::default(),
+        }
+    }
+}
 
-fn url(path: impl Into<String>) -> String {
-    let url = format!("{}{}", BASE_URL, path.into());
-    info!("FETCHING: {}", url);
-    url
+/// Client for the Quaver API.  Cloning is cheap; all clones share the same underlying connection
+/// pool.
+#[derive(Clone)]
+pub struct QuaverClient {
+    client: reqwest::Client,
+    base_url: String,
+    retry: RetryConfig,
+    attempt_counters: Arc<AttemptCounters>,
+    rate_limiter: Arc<RateLimiter>,
+    fixtures: FixtureMode,
+    /// Unknown fields that have already been logged, so that each is only reported once
+    reported_unknown_fields: Arc<Mutex<HashSet<String>>>,
 }
 
-#[derive(Deserialize)]
-#[serde(untagged)]
 pub enum QuaverResponse<T> {
     Success(T),
     Error { status: u32, error: String },
@@ -40,127 +103,392 @@ impl<T> QuaverResponse<T> {
     }
 }
 
+/// Result of looking up a user upstream
+#[derive(Debug)]
+pub enum UserLookup {
+    Found(APIUser),
+    /// Several users matched and none of them exactly
+    Ambiguous(Vec<UserCandidate>),
+    NotFound,
+}
+
 #[derive(Error, Debug)]
 pub enum APIError {
     #[error("Error fetching data from Quaver API: {0:?}")]
     ReqwestError(#[from] reqwest::Error),
     #[error("Bad status returned from Quaver API: {0:?}")]
     BadStatus(u32),
+    #[error("Error decoding response from Quaver API endpoint {endpoint} at {path}: {source}")]
+    Decode {
+        endpoint: String,
+        /// JSON path of the value that failed to decode, like `scores[0].accuracy`
+        path: String,
+        /// The part of the response body around where decoding failed
+        body_excerpt: String,
+        source: serde_json::Error,
+    },
+    #[error("No fixture recorded at {0:?}")]
+    MissingFixture(PathBuf),
+    #[error("Request path {0:?} can't be stored as a fixture")]
+    InvalidFixturePath(String),
     #[error("Error from Quaver API: status={status}, error: {error}")]
     QuaverAPIError { status: u32, error: String },
+    #[error("Giving up after {attempts} attempts: {source}")]
+    RetriesExhausted {
+        attempts: u32,
+        source: Box<APIError>,
+    },
 }
 
-pub async fn get_user_stats(user_id: i64) -> Result<Option<APIStatsUser>, APIError> {
-    info!("get_user_stats user_id={}", user_id);
-    let res_opt = reqwest::get(&url(format!("/v1/users/full/{}/", user_id)))
-        .await?
-        .json::<QuaverResponse<APIGetUserStatsResponse>>()
-        .await?
-        .success()?;
-    let res = match res_opt {
-        Some(res) => res,
-        None => return Ok(None),
-    };
+fn is_retryable_status(status: u32) -> bool {
+    status == 429 || (500..600).contains(&status)
+}
 
-    if res.status != 200 {
-        return Err(APIError::BadStatus(res.status));
+impl APIError {
+    /// Returns `true` if this error is likely to be transient, meaning that the request which
+    /// produced it can be retried.  Anything else (bad requests, undecodable responses, etc.) will
+    /// just fail the same way again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            APIError::ReqwestError(err) => {
+                if err.is_decode() {
+                    false
+                } else if let Some(status) = err.status() {
+                    is_retryable_status(status.as_u16() as u32)
+                } else {
+                    err.is_timeout() || err.is_connect() || err.is_request() || err.is_body()
+                }
+            },
+            APIError::BadStatus(status) => is_retryable_status(*status),
+            APIError::Decode { .. }
+            | APIError::MissingFixture(_)
+            | APIError::InvalidFixturePath(_) => false,
+            APIError::QuaverAPIError { status, .. } => is_retryable_status(*status),
+            APIError::RetriesExhausted { .. } => false,
+        }
     }
-
-    Ok(Some(res.user))
 }
 
-pub async fn get_user_best_scores(
-    user_id: i64,
-    mode_id: i16,
-) -> Result<Option<Vec<APIScore>>, APIError> {
-    info!(
-        "get_user_best_scores user_id={}, mode_id={}",
-        user_id, mode_id
-    );
-    let res_opt = reqwest::get(&url(format!(
-        "/v1/users/scores/best?id={}&mode={}",
-        user_id, mode_id
-    )))
-    .await?
-    .json::<QuaverResponse<APIScoresResponse>>()
-    .await?
-    .success()?;
-    let res = match res_opt {
-        Some(res) => res,
-        None => return Ok(None),
-    };
-
-    if res.status != 200 {
-        return Err(APIError::BadStatus(res.status));
-    }
-
-    Ok(Some(res.scores))
-}
+impl QuaverClient {
+    pub fn new(config: QuaverClientConfig) -> Result<Self, APIError> {
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .connect_timeout(config.connect_timeout)
+            .user_agent(config.user_agent)
+            .build()?;
 
-pub async fn get_user_recent_scores(
-    user_id: i64,
-    mode_id: i16,
-) -> Result<Option<Vec<APIScore>>, APIError> {
-    info!(
-        "get_user_recent_scores user_id={}, mode_id={}",
-        user_id, mode_id
-    );
-    let res_opt = reqwest::get(&url(format!(
-        "/v1/users/scores/recent?id={}&mode={}",
-        user_id, mode_id
-    )))
-    .await?
-    .json::<QuaverResponse<APIScoresResponse>>()
-    .await?
-    .success()?;
-    let res = match res_opt {
-        Some(res) => res,
-        None => return Ok(None),
-    };
-
-    if res.status != 200 {
-        return Err(APIError::BadStatus(res.status));
-    }
-
-    Ok(Some(res.scores))
-}
+        Ok(QuaverClient {
+            client,
+            base_url: config.base_url.trim_end_matches('/').to_owned(),
+            retry: config.retry,
+            attempt_counters: Arc::new(AttemptCounters::default()),
+            rate_limiter: Arc::new(RateLimiter::new(config.rate_limit)),
+            fixtures: config.fixtures,
+            reported_unknown_fields: Arc::new(Mutex::new(HashSet::new())),
+        })
+    }
+
+    /// Totals of how many attempts requests made through this client (and its clones) have taken
+    pub fn attempt_stats(&self) -> AttemptStats {
+        self.attempt_counters.snapshot()
+    }
 
-async fn get_user_by_id(user_id: i64) -> Result<Option<APIUser>, APIError> {
-    let res = reqwest::get(&url(format!("/v1/users?id={}", user_id)))
-        .await?
-        .json::<QuaverResponse<APIGetUsersResponse>>()
-        .await?
-        .success()?
-        .expect("Shouldn't be able to get 404 from this endpoint");
+    fn url(&self, path: impl Into<String>) -> String {
+        let url = format!("{}{}", self.base_url, path.into());
+        info!("FETCHING: {}", url);
+        url
+    }
 
-    Ok(res.users.into_iter().next())
-}
+    /// Serves a request from a recorded fixture rather than the network
+    fn replay<T: DeserializeOwned>(
+        &self,
+        fixtures_dir: &Path,
+        path: &str,
+    ) -> Result<Option<T>, APIError> {
+        let fixture_path = fixture_path(fixtures_dir, path)
+            .ok_or_else(|| APIError::InvalidFixturePath(path.to_owned()))?;
+        info!("REPLAYING: {} from {:?}", path, fixture_path);
+        let body = std::fs::read_to_string(&fixture_path)
+            .map_err(|_| APIError::MissingFixture(fixture_path))?;
+
+        self.decode(path, &body)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, path: &str, body: &str) -> Result<Option<T>, APIError> {
+        let Decoded {
+            response,
+            unknown_fields,
+        } = decode_response(path, body).map_err(|err| {
+            if let APIError::Decode { body_excerpt, .. } = &err {
+                error!("{}; response body near there: {}", err, body_excerpt);
+            }
+            err
+        })?;
+
+        if !unknown_fields.is_empty() {
+            let mut reported_unknown_fields = self.reported_unknown_fields.lock().unwrap();
+            for field in unknown_fields {
+                if reported_unknown_fields.insert(field.clone()) {
+                    warn!("Unknown field {} in response from {}", field, path);
+                }
+            }
+        }
+
+        response.success()
+    }
+
+    /// Stores a raw response body as the fixture for `path`.  Failing to do so is logged but
+    /// otherwise ignored so that recording never breaks the request being made.
+    fn record(&self, path: &str, body: &str) {
+        let fixtures_dir = match &self.fixtures {
+            FixtureMode::Record(fixtures_dir) => fixtures_dir,
+            _ => return,
+        };
+
+        let fixture_path = match fixture_path(fixtures_dir, path) {
+            Some(fixture_path) => fixture_path,
+            None => {
+                warn!(
+                    "Not recording fixture for {:?}; it can't be stored safely",
+                    path
+                );
+                return;
+            },
+        };
+        let res = fixture_path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&fixture_path, body));
+        if let Err(err) = res {
+            warn!("Failed to record fixture at {:?}: {}", fixture_path, err);
+        }
+    }
+
+    async fn get_once<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        url: &str,
+    ) -> Result<Option<T>, APIError> {
+        self.rate_limiter.acquire().await;
+        let res = self.client.get(url).send().await?;
+
+        // Error pages for these aren't JSON, so bail out before trying to decode them
+        let status = res.status();
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = res
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|val| val.to_str().ok())
+                .and_then(|val| val.trim().parse::<u64>().ok())
+                .map(Duration::from_secs);
+            self.rate_limiter.pause(retry_after);
+            return Err(APIError::BadStatus(status.as_u16() as u32));
+        }
+        if status.is_server_error() {
+            return Err(APIError::BadStatus(status.as_u16() as u32));
+        }
+
+        let body = res.text().await?;
+        self.record(path, &body);
+
+        let res = self.decode(path, &body);
+        if let Err(APIError::QuaverAPIError { status: 429, .. }) = res {
+            self.rate_limiter.pause(None);
+        }
+        res
+    }
 
-pub async fn lookup_user(user: &str) -> Result<Option<APIUser>, APIError> {
-    info!("lookup_user user={:?}", user);
+    /// Makes a GET request to the Quaver API, retrying transient failures according to the
+    /// client's `RetryConfig`.  All of the endpoints we use are idempotent so this is always safe.
+    async fn get<T: DeserializeOwned>(&self, path: String) -> Result<Option<T>, APIError> {
+        if let FixtureMode::Replay(fixtures_dir) = &self.fixtures {
+            return self.replay(fixtures_dir, &path);
+        }
+        let url = self.url(path.as_str());
+
+        let mut attempt = 1;
+        loop {
+            match self.get_once(&path, &url).await {
+                Ok(res) => {
+                    if attempt > 1 {
+                        info!("Request to {} succeeded after {} attempts", url, attempt);
+                    }
+                    self.attempt_counters.record(attempt, true);
+                    return Ok(res);
+                },
+                Err(err) if err.is_retryable() && attempt < self.retry.max_attempts => {
+                    let delay = self.retry.backoff(attempt);
+                    warn!(
+                        "Attempt {} of request to {} failed; retrying in {:?}: {}",
+                        attempt, url, delay, err
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                },
+                Err(err) => {
+                    self.attempt_counters.record(attempt, false);
+                    if attempt == 1 {
+                        return Err(err);
+                    }
 
-    // Try to get by ID first if `user` is a valid id
-    if let Ok(parsed_user_id) = user.parse::<i64>() {
-        if let Some(user) = get_user_by_id(parsed_user_id).await? {
-            return Ok(Some(user));
+                    error!(
+                        "Request to {} failed after {} attempts: {}",
+                        url, attempt, err
+                    );
+                    return Err(APIError::RetriesExhausted {
+                        attempts: attempt,
+                        source: Box::new(err),
+                    });
+                },
+            }
         }
     }
 
-    // Try to lookup user by username
-    let res = reqwest::get(&url(format!("/v1/users/search/{}", user)))
-        .await?
-        .json::<QuaverResponse<APISearchUsersResponse>>()
-        .await?
-        .success()?
-        .expect("Shouldn't be able to get 404 from this endpoint");
+    pub async fn get_user_stats(&self, user_id: i64) -> Result<Option<APIStatsUser>, APIError> {
+        info!("get_user_stats user_id={}", user_id);
+        let res_opt = self
+            .get::<APIGetUserStatsResponse>(format!("/v1/users/full/{}/", user_id))
+            .await?;
+        let res = match res_opt {
+            Some(res) => res,
+            None => return Ok(None),
+        };
+
+        if res.status != 200 {
+            return Err(APIError::BadStatus(res.status));
+        }
+
+        Ok(Some(res.user))
+    }
 
-    let user_id: i64 = match res.users.into_iter().next() {
-        Some(user) => user.id,
-        None => return Ok(None),
-    };
+    pub async fn get_user_best_scores(
+        &self,
+        user_id: i64,
+        mode: GameMode,
+    ) -> Result<Option<Vec<APIScore>>, APIError> {
+        info!("get_user_best_scores user_id={}, mode={}", user_id, mode);
+        let res_opt = self
+            .get::<APIScoresResponse>(format!(
+                "/v1/users/scores/best?id={}&mode={}",
+                user_id,
+                mode.id()
+            ))
+            .await?;
+        let res = match res_opt {
+            Some(res) => res,
+            None => return Ok(None),
+        };
 
-    // We found the user by username; now use that id to look them up
-    get_user_by_id(user_id).await
+        if res.status != 200 {
+            return Err(APIError::BadStatus(res.status));
+        }
+
+        Ok(Some(res.scores))
+    }
+
+    pub async fn get_user_recent_scores(
+        &self,
+        user_id: i64,
+        mode: GameMode,
+    ) -> Result<Option<Vec<APIScore>>, APIError> {
+        info!("get_user_recent_scores user_id={}, mode={}", user_id, mode);
+        let res_opt = self
+            .get::<APIScoresResponse>(format!(
+                "/v1/users/scores/recent?id={}&mode={}",
+                user_id,
+                mode.id()
+            ))
+            .await?;
+        let res = match res_opt {
+            Some(res) => res,
+            None => return Ok(None),
+        };
+
+        if res.status != 200 {
+            return Err(APIError::BadStatus(res.status));
+        }
+
+        Ok(Some(res.scores))
+    }
+
+    async fn get_user_by_id(&self, user_id: i64) -> Result<Option<APIUser>, APIError> {
+        let res = self
+            .get::<APIGetUsersResponse>(format!("/v1/users?id={}", user_id))
+            .await?
+            .expect("Shouldn't be able to get 404 from this endpoint");
+
+        Ok(res.users.into_iter().next())
+    }
+
+    /// Looks up a user by ID or username.  If searching by username doesn't return anyone with
+    /// exactly that username, whoever it did return are candidates rather than guessing, even if
+    /// there's only one of them.
+    pub async fn lookup_user(&self, user: &str) -> Result<UserLookup, APIError> {
+        info!("lookup_user user={:?}", user);
+
+        // Try to get by ID first if `user` is a valid id
+        if let Ok(parsed_user_id) = user.parse::<i64>() {
+            if let Some(user) = self.get_user_by_id(parsed_user_id).await? {
+                return Ok(UserLookup::Found(user));
+            }
+        }
+
+        // These would be resolved away as path segments, and aren't anyone's username anyway
+        if user == "." || user == ".." {
+            return Ok(UserLookup::NotFound);
+        }
+
+        // Try to lookup user by username
+        let res = self
+            .get::<APISearchUsersResponse>(format!(
+                "/v1/users/search/{}",
+                utf8_percent_encode(user, PATH_SEGMENT)
+            ))
+            .await?
+            .expect("Shouldn't be able to get 404 from this endpoint");
+
+        let exact_match_ix = res
+            .users
+            .iter()
+            .position(|search_user| search_user.username.eq_ignore_ascii_case(user));
+        let user_id = match exact_match_ix {
+            Some(ix) => res.users[ix].id,
+            None if res.users.is_empty() => return Ok(UserLookup::NotFound),
+            None => {
+                return Ok(UserLookup::Ambiguous(
+                    res.users.into_iter().map(Into::into).collect(),
+                ))
+            },
+        };
+
+        // We found the user by username; now use that id to look them up
+        Ok(match self.get_user_by_id(user_id).await? {
+            Some(user) => UserLookup::Found(user),
+            None => UserLookup::NotFound,
+        })
+    }
+}
+
+#[test]
+fn error_retryability() {
+    assert!(APIError::BadStatus(503).is_retryable());
+    assert!(APIError::BadStatus(429).is_retryable());
+    assert!(!APIError::BadStatus(400).is_retryable());
+    assert!(APIError::QuaverAPIError {
+        status: 500,
+        error: String::new()
+    }
+    .is_retryable());
+    assert!(!APIError::QuaverAPIError {
+        status: 403,
+        error: String::new()
+    }
+    .is_retryable());
+    assert!(!APIError::RetriesExhausted {
+        attempts: 4,
+        source: Box::new(APIError::BadStatus(503))
+    }
+    .is_retryable());
 }
 
 #[test]
@@ -169,5 +497,35 @@ fn stats_deserialization() {
 {"status":200,"user":{"info":{"id":19250,"steam_id":"76561198098147167","username":"ameo","time_registered":"2020-07-15T03:25:26.679Z","allowed":1,"privileges":1,"usergroups":1,"mute_endtime":"1970-01-01T00:00:00.000Z","latest_activity":"2020-08-08T21:50:39.855Z","country":"US","avatar_url":"https://steamcdn-a.akamaihd.net/steamcommunity/public/images/avatars/93/9346acec9e58e4f11e3c095323097ad1982d5adc_full.jpg","userpage":null,"online":false},"profile_badges":[],"activity_feed":[{"id":107284,"type":7,"timestamp":"2020-07-18T03:44:21.839Z","map":{"id":-1,"name":"Perfectionist"}},{"id":106785,"type":7,"timestamp":"2020-07-18T01:55:28.744Z","map":{"id":-1,"name":"Humble Beginnings"}},{"id":99135,"type":7,"timestamp":"2020-07-16T17:30:10.525Z","map":{"id":-1,"name":"Quombo"}},{"id":88115,"type":7,"timestamp":"2020-07-15T03:28:02.143Z","map":{"id":-1,"name":"Baby Steps"}},{"id":88096,"type":0,"timestamp":"2020-07-15T03:25:26.683Z"}],"keys4":{"globalRank":7961,"countryRank":1889,"multiplayerWinRank":4833,"stats":{"user_id":19250,"total_score":133582330,"ranked_score":67926257,"overall_accuracy":89.57049465155498,"overall_performance_rating":50.276700110008136,"play_count":293,"fail_count":80,"max_combo":503,"replays_watched":0,"total_marv":51038,"total_perf":30817,"total_great":7918,"total_good":2589,"total_okay":896,"total_miss":6688,"total_pauses":0,"multiplayer_wins":1,"multiplayer_losses":32,"multiplayer_ties":5}},"keys7":{"globalRank":38698,"countryRank":10104,"multiplayerWinRank":37095,"stats":{"user_id":19250,"total_score":2416,"ranked_score":0,"overall_accuracy":0,"overall_performance_rating":0,"play_count":1,"fail_count":1,"max_combo":2,"replays_watched":0,"total_marv":0,"total_perf":3,"total_great":0,"total_good":0,"total_okay":1,"total_miss":17,"total_pauses":0,"multiplayer_wins":0,"multiplayer_losses":0,"multiplayer_ties":0}}}}
 "#;
 
-    let _: QuaverResponse<APIGetUserStatsResponse> = serde_json::from_str(raw).unwrap();
+    let decoded = decode_response::<APIGetUserStatsResponse>("/v1/users/full/19250/", raw).unwrap();
+    assert!(decoded.unknown_fields.is_empty());
+    let user = decoded.response.success().unwrap().unwrap().user;
+    assert_eq!(
+        user.mode_stats.keys().copied().collect::<Vec<_>>(),
+        GameMode::ALL.to_vec()
+    );
+    assert_eq!(user.mode_stats[&GameMode::Keys4].global_rank, 7961);
+    assert_eq!(user.mode_stats[&GameMode::Keys7].stats.play_count, 1);
+    assert_eq!(
+        user.info.latest_activity_at().unwrap().to_string(),
+        "2020-08-08 21:50:39.855"
+    );
+
+    // Unknown fields and decode failures inside a mode's stats are reported with their full path
+    let drifted = raw
+        .replace(r#""play_count":1,"#, r#""play_count":1,"new_stat":0,"#)
+        .replace(r#""keys7":"#, r#""keys10":{},"keys7":"#);
+    let decoded =
+        decode_response::<APIGetUserStatsResponse>("/v1/users/full/19250/", &drifted).unwrap();
+    assert_eq!(
+        decoded.unknown_fields,
+        vec!["user.keys10", "user.keys7.stats.new_stat"]
+    );
+
+    let broken = raw.replace(r#""play_count":293"#, r#""play_count":"lots""#);
+    match decode_response::<APIGetUserStatsResponse>("/v1/users/full/19250/", &broken) {
+        Err(APIError::Decode { path, .. }) => assert_eq!(path, "user.keys4.stats.play_count"),
+        Err(err) => panic!("Unexpected error: {}", err),
+        Ok(_) => panic!("Invalid play count was accepted"),
+    }
 }
@@ -0,0 +1,141 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Limits on how quickly requests are sent to the Quaver API.  These are shared between every
+/// clone of a `QuaverClient`.
+#[derive(Clone, Debug)]
+pub struct RateLimitConfig {
+    /// Sustained number of requests allowed per second.  Must be greater than zero.
+    pub requests_per_second: f64,
+    /// Maximum number of requests that can be sent back-to-back after a period of inactivity
+    pub burst: u32,
+    /// How long to stop sending requests after upstream responds with 429 and doesn't tell us
+    /// how long to wait via `Retry-After`
+    pub rate_limited_backoff: Duration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            requests_per_second: 5.,
+            burst: 10,
+            rate_limited_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+    paused_until: Option<Instant>,
+}
+
+/// Token bucket rate limiter.  Every request takes one token; tokens are refilled continuously at
+/// `requests_per_second` up to a maximum of `burst`.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        let bucket = Bucket {
+            tokens: config.burst as f64,
+            last_refill: Instant::now(),
+            paused_until: None,
+        };
+
+        RateLimiter {
+            config,
+            bucket: Mutex::new(bucket),
+        }
+    }
+
+    /// Takes a token if one is available at `now`.  Otherwise, returns how long the caller should
+    /// wait before trying again.
+    fn try_acquire_at(&self, now: Instant) -> Result<(), Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+
+        if let Some(paused_until) = bucket.paused_until {
+            if paused_until > now {
+                return Err(paused_until - now);
+            }
+            bucket.paused_until = None;
+        }
+
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * self.config.requests_per_second)
+            .min(self.config.burst as f64);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            return Ok(());
+        }
+
+        Err(Duration::from_secs_f64(
+            (1. - bucket.tokens) / self.config.requests_per_second,
+        ))
+    }
+
+    /// Waits until a request is allowed to be sent
+    pub async fn acquire(&self) {
+        while let Err(wait) = self.try_acquire_at(Instant::now()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Stops all requests from being sent for `duration`, or for the configured default if
+    /// `None`.  Called when upstream tells us that we're sending too many requests.
+    pub fn pause(&self, duration: Option<Duration>) {
+        let duration = duration.unwrap_or(self.config.rate_limited_backoff);
+        warn!("Pausing all Quaver API requests for {:?}", duration);
+
+        let mut bucket = self.bucket.lock().unwrap();
+        let until = Instant::now() + duration;
+        bucket.paused_until = Some(match bucket.paused_until {
+            Some(existing) if existing > until => existing,
+            _ => until,
+        });
+        bucket.tokens = 0.;
+    }
+}
+
+#[test]
+fn token_bucket_refill() {
+    let limiter = RateLimiter::new(RateLimitConfig {
+        requests_per_second: 2.,
+        burst: 3,
+        rate_limited_backoff: Duration::from_secs(30),
+    });
+    let start = limiter.bucket.lock().unwrap().last_refill;
+
+    for _ in 0..3 {
+        assert!(limiter.try_acquire_at(start).is_ok());
+    }
+    assert_eq!(
+        limiter.try_acquire_at(start),
+        Err(Duration::from_millis(500))
+    );
+
+    // Tokens never accumulate past the burst size
+    let later = start + Duration::from_secs(60);
+    for _ in 0..3 {
+        assert!(limiter.try_acquire_at(later).is_ok());
+    }
+    assert!(limiter.try_acquire_at(later).is_err());
+    assert!(limiter
+        .try_acquire_at(later + Duration::from_millis(500))
+        .is_ok());
+}
+
+#[test]
+fn pause_blocks_all_requests() {
+    let limiter = RateLimiter::new(RateLimitConfig::default());
+    limiter.pause(Some(Duration::from_secs(10)));
+
+    let wait = limiter.try_acquire_at(Instant::now()).unwrap_err();
+    assert!(wait > Duration::from_secs(9));
+}
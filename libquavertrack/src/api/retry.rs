@@ -0,0 +1,95 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use rand::Rng;
+
+/// Controls how failed requests to the Quaver API are retried.  Only errors for which
+/// [`super::APIError::is_retryable`] returns `true` are retried.
+#[derive(Clone, Debug)]
+pub struct RetryConfig {
+    /// Total number of attempts made for a request, including the first one
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Returns how long to wait after failed attempt number `attempt` (starting at 1).  The
+    /// exponential delay is capped at `max_delay` and then fully jittered so that requests that
+    /// failed together don't all retry at the same instant.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let capped = self.base_delay.saturating_mul(factor).min(self.max_delay);
+        let millis = capped.as_millis() as u64;
+
+        Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+    }
+}
+
+/// Running totals of how many attempts requests made through a client needed
+#[derive(Default)]
+pub(crate) struct AttemptCounters {
+    requests: AtomicU64,
+    attempts: AtomicU64,
+    retried_requests: AtomicU64,
+    failed_requests: AtomicU64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AttemptStats {
+    pub requests: u64,
+    pub attempts: u64,
+    /// Requests that needed more than one attempt, whether or not they eventually succeeded
+    pub retried_requests: u64,
+    pub failed_requests: u64,
+}
+
+impl AttemptCounters {
+    pub(crate) fn record(&self, attempts: u32, succeeded: bool) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.attempts.fetch_add(attempts as u64, Ordering::Relaxed);
+        if attempts > 1 {
+            self.retried_requests.fetch_add(1, Ordering::Relaxed);
+        }
+        if !succeeded {
+            self.failed_requests.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> AttemptStats {
+        AttemptStats {
+            requests: self.requests.load(Ordering::Relaxed),
+            attempts: self.attempts.load(Ordering::Relaxed),
+            retried_requests: self.retried_requests.load(Ordering::Relaxed),
+            failed_requests: self.failed_requests.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[test]
+fn backoff_is_capped_and_jittered() {
+    let config = RetryConfig {
+        max_attempts: 10,
+        base_delay: Duration::from_millis(100),
+        max_delay: Duration::from_millis(1000),
+    };
+
+    for _ in 0..100 {
+        assert!(config.backoff(1) <= Duration::from_millis(100));
+        assert!(config.backoff(3) <= Duration::from_millis(400));
+        assert!(config.backoff(8) <= Duration::from_millis(1000));
+        assert!(config.backoff(u32::MAX) <= Duration::from_millis(1000));
+    }
+}
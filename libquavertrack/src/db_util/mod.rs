@@ -1,11 +1,18 @@
-use chrono::NaiveDateTime;
-use diesel::{pg::PgConnection, prelude::*};
+use chrono::{NaiveDateTime, Utc};
+use diesel::{
+    pg::{upsert::excluded, PgConnection},
+    prelude::*,
+    sql_types::{Array, BigInt, Bool, Integer, Nullable, SmallInt, Text, Timestamp},
+};
 
 pub mod models;
 pub mod schema;
 
 use self::models::{
-    APIScore, APIStatsUser, APIUser, DBScore, DBStatsUpdate, Map, NewDBStatsUpdate, NewDBUser,
+    APIProfileBadge, APIScore, APIStatsUser, ActivityFeed, BadgeChanges, DBActivityEvent, DBScore,
+    DBStatsUpdate, DBUser, DBUserBadge, DBUserProfile, GameMode, Map, NewDBActivityEvent,
+    NewDBStatsUpdate, NewDBUser, NewDBUserBadge, NewDBUserProfile, StatsHistoryCursor,
+    StatsResolution, TopScore, UserCandidate, UserSearchResult,
 };
 
 pub fn store_maps(conn: &PgConnection, maps: &[Map]) -> Result<(), diesel::result::Error> {
@@ -59,42 +66,451 @@ pub fn store_scores(
     ))
 }
 
+/// Returns the most recent stats snapshot stored for a user in `mode`
+pub fn get_latest_stats_update(
+    conn: &PgConnection,
+    user_id: i64,
+    mode: GameMode,
+) -> Result<Option<DBStatsUpdate>, diesel::result::Error> {
+    use schema::stats_updates;
+
+    stats_updates::table
+        .filter(
+            stats_updates::dsl::user_id
+                .eq(user_id)
+                .and(stats_updates::dsl::mode.eq(mode)),
+        )
+        .order_by(stats_updates::dsl::recorded_at.desc())
+        .first(conn)
+        .optional()
+}
+
+/// Returns when the player last changed the stats in `current`, meaning when its play count and
+/// scores were first recorded.  Ranks are ignored since they drift as other players play.
+pub fn get_player_stats_changed_at(
+    conn: &PgConnection,
+    current: &DBStatsUpdate,
+) -> Result<NaiveDateTime, diesel::result::Error> {
+    use schema::{stats_rollups, stats_updates};
+
+    let first_stored: Option<NaiveDateTime> = stats_updates::table
+        .select(diesel::dsl::min(stats_updates::dsl::recorded_at))
+        .filter(
+            stats_updates::dsl::user_id
+                .eq(current.user_id)
+                .and(stats_updates::dsl::mode.eq(current.mode))
+                .and(stats_updates::dsl::play_count.eq(current.play_count))
+                .and(stats_updates::dsl::total_score.eq(current.total_score))
+                .and(stats_updates::dsl::ranked_score.eq(current.ranked_score)),
+        )
+        .get_result(conn)?;
+    let first_rolled_up: Option<NaiveDateTime> = stats_rollups::table
+        .select(diesel::dsl::min(stats_rollups::dsl::recorded_at))
+        .filter(
+            stats_rollups::dsl::user_id
+                .eq(current.user_id)
+                .and(stats_rollups::dsl::mode.eq(current.mode))
+                .and(stats_rollups::dsl::play_count.eq(current.play_count))
+                .and(stats_rollups::dsl::total_score.eq(current.total_score))
+                .and(stats_rollups::dsl::ranked_score.eq(current.ranked_score)),
+        )
+        .get_result(conn)?;
+
+    Ok(first_stored
+        .into_iter()
+        .chain(first_rolled_up)
+        .min()
+        .unwrap_or(current.recorded_at))
+}
+
+/// Records a new stats snapshot for each mode unless it's identical to the latest one stored for
+/// that user and mode, in which case only that snapshot's `last_checked_at` is bumped.  Returns
+/// the current snapshot for every mode either way.
+///
+/// Concurrent updates for the same user are serialized with a transaction-scoped advisory lock so
+/// that they can't both see the same latest snapshot and store duplicates of it.
 pub fn store_stats_update(
     conn: &PgConnection,
     stats: APIStatsUser,
 ) -> Result<Vec<DBStatsUpdate>, diesel::result::Error> {
     use schema::stats_updates;
 
-    let [update_4k, update_7k] = stats.to_db();
-    let records: &[NewDBStatsUpdate] = &[update_4k, update_7k];
+    let user_id = stats.info.id;
+    let records: Vec<NewDBStatsUpdate> = stats.to_db();
+    let now = Utc::now().naive_utc();
+
+    conn.transaction(|| {
+        diesel::sql_query("SELECT pg_advisory_xact_lock($1)")
+            .bind::<BigInt, _>(user_id)
+            .execute(conn)?;
+
+        records
+            .into_iter()
+            .map(
+                |record| match get_latest_stats_update(conn, record.user_id, record.mode)? {
+                    Some(latest) if record.matches(&latest) => {
+                        diesel::update(stats_updates::table.find(latest.id))
+                            .set(stats_updates::dsl::last_checked_at.eq(now))
+                            .get_result(conn)
+                    },
+                    _ => diesel::insert_into(stats_updates::table)
+                        .values(&record)
+                        .get_result(conn),
+                },
+            )
+            .collect()
+    })
+}
+
+/// Stores any entries from a user's activity feed that we haven't seen before, returning the newly
+/// stored events.
+pub fn store_activity_feed(
+    conn: &PgConnection,
+    user_id: i64,
+    activity_feed: Vec<ActivityFeed>,
+) -> Result<Vec<DBActivityEvent>, diesel::result::Error> {
+    use schema::activity_events;
+
+    let (mut maps, events): (Vec<Map>, Vec<NewDBActivityEvent>) = activity_feed
+        .into_iter()
+        .map(|activity| activity.to_db(user_id))
+        .fold(
+            (Vec::new(), Vec::new()),
+            |(mut maps, mut events), (map, event)| {
+                maps.extend(map);
+                events.push(event);
+
+                (maps, events)
+            },
+        );
 
-    diesel::insert_into(stats_updates::table)
-        .values(records)
-        .returning(stats_updates::all_columns)
+    maps.sort_unstable_by_key(|map| map.id);
+    maps.dedup_by_key(|map| map.id);
+
+    store_maps(conn, &maps)?;
+
+    diesel::insert_into(activity_events::table)
+        .values(&events)
+        .on_conflict_do_nothing()
+        .returning(activity_events::all_columns)
         .get_results(conn)
 }
 
-pub fn get_stats_updates_for_user(
+/// Records the badges that a user currently has, marking any badges they no longer have as
+/// removed.  Returns the badges that were gained or removed since the last time this was called.
+/// If this is the user's first update, nothing is reported as gained since we can't tell when they
+/// got them.  Badges that come back after being removed count as newly gained.
+pub fn store_badges(
     conn: &PgConnection,
     user_id: i64,
-    mode: i16,
-) -> Result<Vec<DBStatsUpdate>, diesel::result::Error> {
-    use schema::stats_updates;
+    mut badges: Vec<APIProfileBadge>,
+    is_first_update: bool,
+) -> Result<BadgeChanges, diesel::result::Error> {
+    use schema::user_badges;
 
-    stats_updates::table
-        .filter(
-            stats_updates::dsl::user_id
+    // Upserting the same badge twice in one statement fails
+    badges.sort_unstable_by_key(|badge| badge.id);
+    badges.dedup_by_key(|badge| badge.id);
+
+    let now = Utc::now().naive_utc();
+    let stored_badges: Vec<(i64, Option<NaiveDateTime>)> = user_badges::table
+        .filter(user_badges::dsl::user_id.eq(user_id))
+        .select((user_badges::dsl::badge_id, user_badges::dsl::removed_at))
+        .load(conn)?;
+    let held_badge_ids: Vec<i64> = stored_badges
+        .iter()
+        .filter(|(_, removed_at)| removed_at.is_none())
+        .map(|(badge_id, _)| *badge_id)
+        .collect();
+
+    let current_badge_ids: Vec<i64> = badges.iter().map(|badge| badge.id).collect();
+    let removed_badge_ids: Vec<i64> = held_badge_ids
+        .iter()
+        .copied()
+        .filter(|badge_id| !current_badge_ids.contains(badge_id))
+        .collect();
+
+    let returning_badge_ids: Vec<i64> = stored_badges
+        .iter()
+        .filter(|(badge_id, removed_at)| {
+            removed_at.is_some() && current_badge_ids.contains(badge_id)
+        })
+        .map(|(badge_id, _)| *badge_id)
+        .collect();
+    diesel::update(
+        user_badges::table.filter(
+            user_badges::dsl::user_id
                 .eq(user_id)
-                .and(stats_updates::dsl::mode.eq(mode)),
+                .and(user_badges::dsl::badge_id.eq_any(returning_badge_ids)),
+        ),
+    )
+    .set(user_badges::dsl::first_seen_at.eq(now))
+    .execute(conn)?;
+
+    let new_badges: Vec<NewDBUserBadge> = badges
+        .into_iter()
+        .map(|badge| NewDBUserBadge {
+            user_id,
+            badge_id: badge.id,
+            name: badge.name,
+            description: badge.description,
+            first_seen_at: now,
+            last_seen_at: now,
+        })
+        .collect();
+    let stored: Vec<DBUserBadge> = diesel::insert_into(user_badges::table)
+        .values(&new_badges)
+        .on_conflict((user_badges::dsl::user_id, user_badges::dsl::badge_id))
+        .do_update()
+        .set((
+            user_badges::dsl::name.eq(excluded(user_badges::dsl::name)),
+            user_badges::dsl::description.eq(excluded(user_badges::dsl::description)),
+            user_badges::dsl::last_seen_at.eq(now),
+            user_badges::dsl::removed_at.eq(None::<NaiveDateTime>),
+        ))
+        .returning(user_badges::all_columns)
+        .get_results(conn)?;
+    let gained = if is_first_update {
+        Vec::new()
+    } else {
+        stored
+            .into_iter()
+            .filter(|badge| !held_badge_ids.contains(&badge.badge_id))
+            .collect()
+    };
+
+    let removed = diesel::update(
+        user_badges::table.filter(
+            user_badges::dsl::user_id
+                .eq(user_id)
+                .and(user_badges::dsl::badge_id.eq_any(removed_badge_ids)),
+        ),
+    )
+    .set(user_badges::dsl::removed_at.eq(now))
+    .returning(user_badges::all_columns)
+    .get_results(conn)?;
+
+    Ok(BadgeChanges { gained, removed })
+}
+
+/// Returns up to `limit` of a user's activity events, newest first.  If `before` is provided, only
+/// events with an id lower than it are returned so that it can be used as a pagination cursor.
+pub fn get_activity_for_user(
+    conn: &PgConnection,
+    user_id: i64,
+    before: Option<i64>,
+    limit: i64,
+) -> Result<(Vec<Map>, Vec<DBActivityEvent>), diesel::result::Error> {
+    use schema::{activity_events, maps};
+
+    let mut query = activity_events::table
+        .filter(activity_events::dsl::user_id.eq(user_id))
+        .into_boxed();
+    if let Some(before) = before {
+        query = query.filter(activity_events::dsl::id.lt(before));
+    }
+    let events: Vec<DBActivityEvent> = query
+        .order_by(activity_events::dsl::id.desc())
+        .limit(limit)
+        .load(conn)?;
+    let all_map_ids: Vec<i64> = events.iter().filter_map(|event| event.map_id).collect();
+
+    let maps: Vec<Map> = maps::table
+        .filter(maps::dsl::id.eq_any(all_map_ids))
+        .load(conn)?;
+
+    Ok((maps, events))
+}
+
+/// Filters and pagination for `get_stats_history_for_user`
+#[derive(Clone, Debug)]
+pub struct StatsHistoryQuery {
+    /// Only include snapshots recorded at or after this time
+    pub from: Option<NaiveDateTime>,
+    /// Only include snapshots recorded at or before this time
+    pub to: Option<NaiveDateTime>,
+    /// The last snapshot of the previous page.  Only snapshots that come after it, ordered by
+    /// `recorded_at` and then id, or from later buckets are returned.
+    pub after: Option<StatsHistoryCursor>,
+    pub resolution: StatsResolution,
+    pub limit: i64,
+}
+
+/// All of a user's snapshots for a mode, whether or not they've been rolled up
+const USER_STATS_HISTORY: &str = "(
+    SELECT * FROM stats_updates WHERE user_id = $1 AND mode = $2
+    UNION ALL
+    SELECT * FROM stats_rollups WHERE user_id = $1 AND mode = $2
+) history";
+
+/// Returns up to `query.limit` of a user's stats snapshots for `mode`, oldest first, including
+/// ones that have been rolled up.  At resolutions other than raw, snapshots are bucketed by hour,
+/// day or week and only the last one recorded in each bucket is returned.
+pub fn get_stats_history_for_user(
+    conn: &PgConnection,
+    user_id: i64,
+    mode: GameMode,
+    query: &StatsHistoryQuery,
+) -> Result<Vec<DBStatsUpdate>, diesel::result::Error> {
+    let (after, after_id) = match query.after {
+        Some(cursor) => (Some(cursor.recorded_at), Some(cursor.id)),
+        None => (None, None),
+    };
+
+    match query.resolution.date_trunc_field() {
+        None => diesel::sql_query(format!(
+            "SELECT * FROM {}
+            WHERE ($3::timestamp IS NULL OR recorded_at >= $3)
+                AND ($4::timestamp IS NULL OR recorded_at <= $4)
+                AND ($5::timestamp IS NULL OR (recorded_at, id) > ($5, $7))
+            ORDER BY recorded_at, id
+            LIMIT $6",
+            USER_STATS_HISTORY
+        ))
+        .bind::<BigInt, _>(user_id)
+        .bind::<SmallInt, _>(mode)
+        .bind::<Nullable<Timestamp>, _>(query.from)
+        .bind::<Nullable<Timestamp>, _>(query.to)
+        .bind::<Nullable<Timestamp>, _>(after)
+        .bind::<BigInt, _>(query.limit)
+        .bind::<Nullable<Integer>, _>(after_id)
+        .load(conn),
+        Some(field) => diesel::sql_query(format!(
+            "SELECT * FROM (
+                SELECT DISTINCT ON (date_trunc($7, recorded_at)) *
+                FROM {}
+                WHERE ($3::timestamp IS NULL OR recorded_at >= $3)
+                    AND ($4::timestamp IS NULL OR recorded_at <= $4)
+                    AND ($5::timestamp IS NULL
+                        OR date_trunc($7, recorded_at) > date_trunc($7, $5))
+                ORDER BY date_trunc($7, recorded_at), recorded_at DESC, id DESC
+            ) buckets
+            ORDER BY recorded_at, id
+            LIMIT $6",
+            USER_STATS_HISTORY
+        ))
+        .bind::<BigInt, _>(user_id)
+        .bind::<SmallInt, _>(mode)
+        .bind::<Nullable<Timestamp>, _>(query.from)
+        .bind::<Nullable<Timestamp>, _>(query.to)
+        .bind::<Nullable<Timestamp>, _>(after)
+        .bind::<BigInt, _>(query.limit)
+        .bind::<Text, _>(field)
+        .load(conn),
+    }
+}
+
+/// Number of snapshots affected by a run of `roll_up_stats_updates`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RollupCounts {
+    /// Snapshots moved out of `stats_updates` into `stats_rollups`
+    pub rolled_up: usize,
+    /// Rolled up snapshots that were deleted because they were neither the first nor the last in
+    /// their bucket
+    pub pruned: usize,
+}
+
+/// Number of snapshots of one user and mode that were moved into `stats_rollups`
+#[derive(QueryableByName)]
+struct RolledUpCount {
+    #[sql_type = "BigInt"]
+    user_id: i64,
+    #[sql_type = "SmallInt"]
+    mode: i16,
+    #[sql_type = "BigInt"]
+    count: i64,
+}
+
+/// Moves snapshots recorded before `daily_cutoff` into `stats_rollups`, then thins rolled up
+/// snapshots out to the first and last one per day, or per week for ones recorded before
+/// `weekly_cutoff`.  Both cutoffs should be aligned to the start of a bucket; see
+/// `RetentionPolicy::cutoffs`.
+///
+/// `previous_weekly_cutoff` is the `weekly_cutoff` of the last successful run, if known.  Only the
+/// history of users that just had snapshots rolled up is thinned out in full.  For everyone else,
+/// only snapshots from `previous_weekly_cutoff` onwards are, since that's all that can have changed
+/// resolution since then.  If it's `None`, everything is thinned out.
+///
+/// A user's latest snapshot in each mode is always left in `stats_updates` since new snapshots are
+/// deduplicated against it.  Running this again with the same cutoffs doesn't change anything.
+pub fn roll_up_stats_updates(
+    conn: &PgConnection,
+    daily_cutoff: NaiveDateTime,
+    weekly_cutoff: NaiveDateTime,
+    previous_weekly_cutoff: Option<NaiveDateTime>,
+) -> Result<RollupCounts, diesel::result::Error> {
+    conn.transaction(|| {
+        let rolled_up_counts: Vec<RolledUpCount> = diesel::sql_query(
+            "WITH rolled_up AS (
+                DELETE FROM stats_updates
+                WHERE recorded_at < $1
+                    AND EXISTS (
+                        SELECT 1 FROM stats_updates newer
+                        WHERE newer.user_id = stats_updates.user_id
+                            AND newer.mode = stats_updates.mode
+                            AND newer.recorded_at > stats_updates.recorded_at
+                    )
+                RETURNING *
+            ), inserted AS (
+                INSERT INTO stats_rollups SELECT * FROM rolled_up ON CONFLICT (id) DO NOTHING
+                RETURNING user_id, mode
+            )
+            SELECT user_id, mode, COUNT(*) AS count FROM inserted GROUP BY user_id, mode",
         )
-        .order_by(stats_updates::dsl::recorded_at.asc())
-        .load(conn)
+        .bind::<Timestamp, _>(daily_cutoff)
+        .load(conn)?;
+        let rolled_up = rolled_up_counts
+            .iter()
+            .map(|counts| counts.count as usize)
+            .sum();
+        let (user_ids, modes): (Vec<i64>, Vec<i16>) = rolled_up_counts
+            .iter()
+            .map(|counts| (counts.user_id, counts.mode))
+            .unzip();
+
+        let pruned = diesel::sql_query(
+            "DELETE FROM stats_rollups WHERE id IN (
+                SELECT id FROM (
+                    SELECT
+                        id,
+                        ROW_NUMBER() OVER (
+                            PARTITION BY user_id, mode, bucket ORDER BY recorded_at, id
+                        ) AS from_first,
+                        ROW_NUMBER() OVER (
+                            PARTITION BY user_id, mode, bucket ORDER BY recorded_at DESC, id DESC
+                        ) AS from_last
+                    FROM (
+                        SELECT
+                            id, user_id, mode, recorded_at,
+                            CASE WHEN recorded_at < $1
+                                THEN date_trunc('week', recorded_at)
+                                ELSE date_trunc('day', recorded_at)
+                            END AS bucket
+                        FROM stats_rollups
+                        WHERE $4::timestamp IS NULL
+                            OR recorded_at >= $4
+                            OR (user_id, mode) IN (
+                                SELECT * FROM unnest($2::bigint[], $3::smallint[])
+                            )
+                    ) rollups
+                ) ranked
+                WHERE from_first > 1 AND from_last > 1
+            )",
+        )
+        .bind::<Timestamp, _>(weekly_cutoff)
+        .bind::<Array<BigInt>, _>(user_ids)
+        .bind::<Array<SmallInt>, _>(modes)
+        .bind::<Nullable<Timestamp>, _>(previous_weekly_cutoff)
+        .execute(conn)?;
+
+        Ok(RollupCounts { rolled_up, pruned })
+    })
 }
 
 pub fn get_scores_for_user(
     conn: &PgConnection,
     user_id: i64,
-    mode: i16,
+    mode: GameMode,
 ) -> Result<(Vec<Map>, Vec<DBScore>), diesel::result::Error> {
     use schema::{maps, scores};
 
@@ -115,18 +531,77 @@ pub fn get_scores_for_user(
     Ok((maps, scores))
 }
 
+/// Returns the user's highest rated score on each map, best first, along with the maps they were
+/// set on.  If `per_mods` is set, the best score for every combination of mods and rate played on
+/// each map is returned instead.  Scores that upstream flagged as personal bests but which were
+/// later beaten are never included.
+pub fn get_top_scores_for_user(
+    conn: &PgConnection,
+    user_id: i64,
+    mode: GameMode,
+    per_mods: bool,
+) -> Result<(Vec<Map>, Vec<TopScore>), diesel::result::Error> {
+    use schema::maps;
+
+    let scores: Vec<TopScore> = diesel::sql_query(
+        "SELECT * FROM (
+            SELECT DISTINCT ON (map_id, CASE WHEN $3 THEN mods ELSE 0 END)
+                scores.*,
+                COUNT(*) OVER (PARTITION BY map_id) AS play_count
+            FROM scores
+            WHERE user_id = $1 AND mode = $2
+            ORDER BY map_id, CASE WHEN $3 THEN mods ELSE 0 END, performance_rating DESC, time
+        ) best
+        ORDER BY performance_rating DESC, time",
+    )
+    .bind::<BigInt, _>(user_id)
+    .bind::<SmallInt, _>(mode)
+    .bind::<Bool, _>(per_mods)
+    .load(conn)?;
+    let all_map_ids: Vec<i64> = scores.iter().map(|top| top.score.map_id).collect();
+
+    let maps: Vec<Map> = maps::table
+        .filter(maps::dsl::id.eq_any(all_map_ids))
+        .load(conn)?;
+
+    Ok((maps, scores))
+}
+
+/// Returns all of a user's scores on a map, oldest first, along with the map if it's been stored
+pub fn get_map_scores_for_user(
+    conn: &PgConnection,
+    user_id: i64,
+    mode: GameMode,
+    map_id: i64,
+) -> Result<(Option<Map>, Vec<DBScore>), diesel::result::Error> {
+    use schema::{maps, scores};
+
+    let map: Option<Map> = maps::table.find(map_id).first(conn).optional()?;
+    let scores: Vec<DBScore> = scores::table
+        .filter(
+            scores::dsl::user_id
+                .eq(user_id)
+                .and(scores::dsl::mode.eq(mode))
+                .and(scores::dsl::map_id.eq(map_id)),
+        )
+        .order_by((scores::dsl::time.asc(), scores::dsl::id.asc()))
+        .load(conn)?;
+
+    Ok((map, scores))
+}
+
 pub fn get_last_update_timestamp(
     conn: &PgConnection,
     user_id: i64,
 ) -> Result<Option<NaiveDateTime>, diesel::result::Error> {
-    use schema::stats_updates;
+    use schema::users;
 
-    stats_updates::table
-        .filter(stats_updates::user_id.eq(user_id))
-        .order_by(stats_updates::recorded_at.desc())
-        .select(stats_updates::dsl::recorded_at)
+    users::table
+        .find(user_id)
+        .select(users::dsl::last_updated_at)
         .first(conn)
         .optional()
+        .map(Option::flatten)
 }
 
 pub fn get_user_id_by_username(
@@ -155,12 +630,224 @@ pub fn get_username_by_user_id(
         .optional()
 }
 
-pub fn store_user(conn: &PgConnection, user: &APIUser) -> Result<(), diesel::result::Error> {
+/// Inserts a user or refreshes the profile of an existing one.  If their username, country, avatar
+/// or usergroups changed (or this is the first time they're stored), a new entry is added to their
+/// profile history.
+pub fn store_user(
+    conn: &PgConnection,
+    user: NewDBUser,
+    now: NaiveDateTime,
+) -> Result<DBUser, diesel::result::Error> {
+    use schema::{user_profile_history, users};
+
+    conn.transaction(|| {
+        let stored: DBUser = diesel::insert_into(users::table)
+            .values(&user)
+            .on_conflict(users::dsl::id)
+            .do_update()
+            .set(&user)
+            .returning(users::all_columns)
+            .get_result(conn)?;
+
+        let profile = NewDBUserProfile::new(&stored, now);
+        let latest_profile: Option<DBUserProfile> = user_profile_history::table
+            .filter(user_profile_history::dsl::user_id.eq(stored.id))
+            .order_by((
+                user_profile_history::dsl::recorded_at.desc(),
+                user_profile_history::dsl::id.desc(),
+            ))
+            .first(conn)
+            .optional()?;
+        if !latest_profile.is_some_and(|latest_profile| profile.matches(&latest_profile)) {
+            diesel::insert_into(user_profile_history::table)
+                .values(&profile)
+                .execute(conn)?;
+        }
+
+        Ok(stored)
+    })
+}
+
+/// Returns every tracked user that has ever had `username`, ignoring case and accents
+pub fn get_users_by_previous_username(
+    conn: &PgConnection,
+    username: &str,
+) -> Result<Vec<UserCandidate>, diesel::result::Error> {
+    diesel::sql_query(
+        "SELECT DISTINCT ON (users.id) users.id, users.username, \
+           history.username AS matched_username, NULL::REAL AS similarity \
+         FROM user_profile_history history \
+         INNER JOIN users ON users.id = history.user_id \
+         WHERE immutable_unaccent(history.username) = immutable_unaccent($1) \
+         ORDER BY users.id, history.recorded_at DESC",
+    )
+    .bind::<Text, _>(username.to_lowercase())
+    .load(conn)
+}
+
+/// Finds up to `limit` tracked users with a current or previous username similar to `query`,
+/// ignoring case and accents.  Each user is returned once along with their best-matching
+/// username, most similar first.
+pub fn search_users(
+    conn: &PgConnection,
+    query: &str,
+    limit: i64,
+) -> Result<Vec<UserCandidate>, diesel::result::Error> {
+    diesel::sql_query(
+        "SELECT * FROM ( \
+           SELECT DISTINCT ON (users.id) users.id, users.username, \
+             history.username AS matched_username, \
+             similarity(immutable_unaccent(history.username), immutable_unaccent($1)) AS similarity \
+           FROM user_profile_history history \
+           INNER JOIN users ON users.id = history.user_id \
+           WHERE immutable_unaccent(history.username) % immutable_unaccent($1) \
+           ORDER BY users.id, similarity DESC \
+         ) candidates \
+         ORDER BY similarity DESC, id \
+         LIMIT $2",
+    )
+    .bind::<Text, _>(query.to_lowercase())
+    .bind::<BigInt, _>(limit)
+    .load(conn)
+}
+
+/// Searches tracked users for autocompletion, matching usernames (current or previous) that
+/// start with `query` or are similar to it, ignoring case and accents.  Exact matches come first,
+/// followed by prefix matches and then fuzzy ones.  Within those, users whose stats changed
+/// recently and often are ranked higher, since those are who people tend to look up.
+pub fn search_tracked_users(
+    conn: &PgConnection,
+    query: &str,
+    now: NaiveDateTime,
+    limit: i64,
+) -> Result<Vec<UserSearchResult>, diesel::result::Error> {
+    let query = query.to_lowercase();
+    let prefix_pattern = format!(
+        "{}%",
+        query
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    );
+
+    diesel::sql_query(
+        "WITH matches AS ( \
+           SELECT * FROM ( \
+             SELECT DISTINCT ON (history.user_id) history.user_id, \
+               history.username AS matched_username, \
+               CASE \
+                 WHEN immutable_unaccent(history.username) = immutable_unaccent($1) THEN 3 \
+                 WHEN immutable_unaccent(history.username) LIKE immutable_unaccent($2) THEN 2 \
+                 ELSE similarity(immutable_unaccent(history.username), immutable_unaccent($1)) \
+               END AS match_rank \
+             FROM user_profile_history history \
+             WHERE immutable_unaccent(history.username) LIKE immutable_unaccent($2) \
+               OR immutable_unaccent(history.username) % immutable_unaccent($1) \
+             ORDER BY history.user_id, match_rank DESC, history.recorded_at DESC \
+           ) best_matches \
+           ORDER BY match_rank DESC \
+           LIMIT 200 \
+         ), \
+         activity AS ( \
+           SELECT matches.user_id, \
+             COUNT(stats_updates.id) FILTER ( \
+               WHERE stats_updates.recorded_at > $3 - INTERVAL '30 days' \
+             ) AS recent_changes \
+           FROM matches \
+           LEFT JOIN stats_updates ON stats_updates.user_id = matches.user_id \
+           GROUP BY matches.user_id \
+         ) \
+         SELECT users.id, users.username, matches.matched_username, users.avatar_url, \
+           users.country, \
+           (SELECT global_rank FROM stats_updates \
+             WHERE user_id = users.id AND mode = $4 \
+             ORDER BY recorded_at DESC LIMIT 1) AS global_rank_4k, \
+           (SELECT global_rank FROM stats_updates \
+             WHERE user_id = users.id AND mode = $5 \
+             ORDER BY recorded_at DESC LIMIT 1) AS global_rank_7k, \
+           users.last_updated_at, \
+           (matches.match_rank \
+             + 0.5 * activity.recent_changes / (activity.recent_changes + 10.0) \
+             + 0.5 * COALESCE( \
+               POWER(0.5, GREATEST(EXTRACT(EPOCH FROM $3 - users.last_updated_at), 0) / 604800), \
+               0 \
+             ))::DOUBLE PRECISION AS rank \
+         FROM matches \
+         INNER JOIN users ON users.id = matches.user_id \
+         INNER JOIN activity ON activity.user_id = matches.user_id \
+         ORDER BY rank DESC, users.id \
+         LIMIT $6",
+    )
+    .bind::<Text, _>(&query)
+    .bind::<Text, _>(prefix_pattern)
+    .bind::<Timestamp, _>(now)
+    .bind::<SmallInt, _>(GameMode::Keys4.id())
+    .bind::<SmallInt, _>(GameMode::Keys7.id())
+    .bind::<BigInt, _>(limit)
+    .load(conn)
+}
+
+/// Returns up to `limit` users that are due to be refreshed at `now`, most overdue first.  Users
+/// that have never been scheduled are returned before all others.
+pub fn get_due_user_ids(
+    conn: &PgConnection,
+    now: NaiveDateTime,
+    limit: i64,
+) -> Result<Vec<i64>, diesel::result::Error> {
+    use schema::users;
+
+    users::table
+        .filter(
+            users::dsl::next_update_at
+                .is_null()
+                .or(users::dsl::next_update_at.le(now)),
+        )
+        .order_by(users::dsl::next_update_at.asc().nulls_first())
+        .limit(limit)
+        .select(users::dsl::id)
+        .load(conn)
+}
+
+pub fn set_next_update_at(
+    conn: &PgConnection,
+    user_id: i64,
+    next_update_at: NaiveDateTime,
+) -> Result<(), diesel::result::Error> {
+    use schema::users;
+
+    diesel::update(users::table.find(user_id))
+        .set(users::dsl::next_update_at.eq(next_update_at))
+        .execute(conn)
+        .map(drop)
+}
+
+/// Returns the number of scores that a user has set since `since`
+pub fn count_scores_since(
+    conn: &PgConnection,
+    user_id: i64,
+    since: NaiveDateTime,
+) -> Result<i64, diesel::result::Error> {
+    use schema::scores;
+
+    scores::table
+        .filter(
+            scores::dsl::user_id
+                .eq(user_id)
+                .and(scores::dsl::time.ge(since)),
+        )
+        .count()
+        .get_result(conn)
+}
+
+pub fn set_last_updated_at(
+    conn: &PgConnection,
+    user_id: i64,
+    last_updated_at: NaiveDateTime,
+) -> Result<(), diesel::result::Error> {
     use schema::users;
 
-    let new_db_user: NewDBUser = user.clone().into();
-    diesel::insert_into(users::table)
-        .values(new_db_user)
+    diesel::update(users::table.find(user_id))
+        .set(users::dsl::last_updated_at.eq(last_updated_at))
         .execute(conn)
         .map(drop)
 }
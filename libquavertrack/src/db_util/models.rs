@@ -1,7 +1,413 @@
-use chrono::{DateTime, NaiveDateTime, Utc};
-use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt::{self, Display},
+    io::Write,
+    ops::BitOr,
+    str::FromStr,
+};
 
-use crate::db_util::schema::{maps, scores, stats_updates, users};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use diesel::{
+    deserialize::{self, FromSql},
+    pg::Pg,
+    serialize::{self, Output, ToSql},
+    sql_types::{Float4, Float8, Int2, Int8, Nullable, Text, Timestamp},
+};
+use serde::{
+    de::{Error as _, IgnoredAny, MapAccess, Visitor},
+    ser::SerializeStruct,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use thiserror::Error;
+
+use crate::db_util::schema::{
+    activity_events, maps, scores, stats_updates, user_badges, user_profile_history, users,
+};
+
+/// A Quaver key mode.  Stored in the database and serialized to JSON as the numeric mode id that
+/// the Quaver API uses.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    AsExpression,
+    FromSqlRow,
+    Serialize,
+    Deserialize,
+)]
+#[sql_type = "Int2"]
+#[serde(into = "i16", try_from = "i16")]
+pub enum GameMode {
+    Keys4 = 1,
+    Keys7 = 2,
+}
+
+#[derive(Debug, Error)]
+#[error("Invalid game mode: {0:?}")]
+pub struct InvalidModeError(pub String);
+
+impl GameMode {
+    pub const ALL: [GameMode; 2] = [GameMode::Keys4, GameMode::Keys7];
+
+    /// The numeric id used for this mode by the Quaver API
+    pub fn id(self) -> i16 {
+        self as i16
+    }
+
+    /// The key under which the full user endpoint returns stats for this mode
+    pub fn api_key(self) -> &'static str {
+        match self {
+            GameMode::Keys4 => "keys4",
+            GameMode::Keys7 => "keys7",
+        }
+    }
+
+    pub fn from_api_key(key: &str) -> Option<GameMode> {
+        GameMode::ALL
+            .iter()
+            .copied()
+            .find(|mode| mode.api_key() == key)
+    }
+}
+
+impl Display for GameMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GameMode::Keys4 => write!(f, "4K"),
+            GameMode::Keys7 => write!(f, "7K"),
+        }
+    }
+}
+
+impl From<GameMode> for i16 {
+    fn from(mode: GameMode) -> Self {
+        mode.id()
+    }
+}
+
+impl TryFrom<i16> for GameMode {
+    type Error = InvalidModeError;
+
+    fn try_from(id: i16) -> Result<Self, Self::Error> {
+        match id {
+            1 => Ok(GameMode::Keys4),
+            2 => Ok(GameMode::Keys7),
+            _ => Err(InvalidModeError(id.to_string())),
+        }
+    }
+}
+
+impl FromStr for GameMode {
+    type Err = InvalidModeError;
+
+    /// Accepts either the Quaver API mode id or the key count, optionally prefixed or suffixed
+    /// with "k" or "keys": "1", "4", "4k", "k4" and "keys4" are all 4K.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "1" | "4" | "4k" | "k4" | "keys4" => Ok(GameMode::Keys4),
+            "2" | "7" | "7k" | "k7" | "keys7" => Ok(GameMode::Keys7),
+            _ => Err(InvalidModeError(s.to_owned())),
+        }
+    }
+}
+
+impl ToSql<Int2, Pg> for GameMode {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        <i16 as ToSql<Int2, Pg>>::to_sql(&self.id(), out)
+    }
+}
+
+impl FromSql<Int2, Pg> for GameMode {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        let id = <i16 as FromSql<Int2, Pg>>::from_sql(bytes)?;
+        GameMode::try_from(id).map_err(Into::into)
+    }
+}
+
+#[cfg(feature = "rocket")]
+impl<'a> rocket::request::FromParam<'a> for GameMode {
+    type Error = InvalidModeError;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        param.parse()
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Invalid resolution: {0:?}; expected one of raw, hourly, daily or weekly")]
+pub struct InvalidResolutionError(pub String);
+
+/// Granularity to return stats history at.  Coarser resolutions keep only the last snapshot
+/// recorded in each hour, day or week.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StatsResolution {
+    #[default]
+    Raw,
+    Hourly,
+    Daily,
+    Weekly,
+}
+
+impl StatsResolution {
+    /// The Postgres `date_trunc` field that snapshots are bucketed by, if any
+    pub fn date_trunc_field(self) -> Option<&'static str> {
+        match self {
+            StatsResolution::Raw => None,
+            StatsResolution::Hourly => Some("hour"),
+            StatsResolution::Daily => Some("day"),
+            StatsResolution::Weekly => Some("week"),
+        }
+    }
+}
+
+impl FromStr for StatsResolution {
+    type Err = InvalidResolutionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "raw" => Ok(StatsResolution::Raw),
+            "hour" | "hourly" => Ok(StatsResolution::Hourly),
+            "day" | "daily" => Ok(StatsResolution::Daily),
+            "week" | "weekly" => Ok(StatsResolution::Weekly),
+            _ => Err(InvalidResolutionError(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Invalid cursor: {0:?}")]
+pub struct InvalidCursorError(pub String);
+
+/// Where a page of stats history left off: the last snapshot on it.  Clients get this as an opaque
+/// string, which holds the snapshot's `recorded_at` as well as its id so that the next page can be
+/// found even if the snapshot itself has since been rolled up or pruned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StatsHistoryCursor {
+    pub recorded_at: NaiveDateTime,
+    pub id: i32,
+}
+
+impl From<&DBStatsUpdate> for StatsHistoryCursor {
+    fn from(update: &DBStatsUpdate) -> Self {
+        StatsHistoryCursor {
+            recorded_at: update.recorded_at,
+            id: update.id,
+        }
+    }
+}
+
+impl Display for StatsHistoryCursor {
+    /// Microseconds since the Unix epoch and the id, like `1610000000000000_123`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let recorded_at = Utc.from_utc_datetime(&self.recorded_at);
+        write!(f, "{}_{}", recorded_at.timestamp_micros(), self.id)
+    }
+}
+
+impl FromStr for StatsHistoryCursor {
+    type Err = InvalidCursorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidCursorError(s.to_owned());
+        let (micros, id) = s.split_once('_').ok_or_else(invalid)?;
+        let micros: i64 = micros.parse().map_err(|_| invalid())?;
+        let recorded_at = Utc
+            .timestamp_opt(
+                micros.div_euclid(1_000_000),
+                (micros.rem_euclid(1_000_000) * 1000) as u32,
+            )
+            .single()
+            .ok_or_else(invalid)?
+            .naive_utc();
+
+        Ok(StatsHistoryCursor {
+            recorded_at,
+            id: id.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Bitmask of the mods a score was set with, using the same bit layout as the Quaver client.
+/// Bits that we don't know about are preserved as-is.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, AsExpression, FromSqlRow)]
+#[sql_type = "Int8"]
+pub struct Mods(pub i64);
+
+impl Mods {
+    pub const NONE: Mods = Mods(0);
+    pub const NO_SLIDER_VELOCITY: Mods = Mods(1 << 0);
+    pub const SPEED_050X: Mods = Mods(1 << 1);
+    pub const SPEED_060X: Mods = Mods(1 << 2);
+    pub const SPEED_070X: Mods = Mods(1 << 3);
+    pub const SPEED_080X: Mods = Mods(1 << 4);
+    pub const SPEED_090X: Mods = Mods(1 << 5);
+    pub const SPEED_110X: Mods = Mods(1 << 6);
+    pub const SPEED_120X: Mods = Mods(1 << 7);
+    pub const SPEED_130X: Mods = Mods(1 << 8);
+    pub const SPEED_140X: Mods = Mods(1 << 9);
+    pub const SPEED_150X: Mods = Mods(1 << 10);
+    pub const SPEED_160X: Mods = Mods(1 << 11);
+    pub const SPEED_170X: Mods = Mods(1 << 12);
+    pub const SPEED_180X: Mods = Mods(1 << 13);
+    pub const SPEED_190X: Mods = Mods(1 << 14);
+    pub const SPEED_200X: Mods = Mods(1 << 15);
+    pub const STRICT: Mods = Mods(1 << 16);
+    pub const CHILL: Mods = Mods(1 << 17);
+    pub const NO_PAUSE: Mods = Mods(1 << 18);
+    pub const AUTOPLAY: Mods = Mods(1 << 19);
+    pub const PAUSED: Mods = Mods(1 << 20);
+    pub const NO_FAIL: Mods = Mods(1 << 21);
+    pub const NO_LONG_NOTES: Mods = Mods(1 << 22);
+    pub const RANDOMIZE: Mods = Mods(1 << 23);
+    pub const SPEED_055X: Mods = Mods(1 << 24);
+    pub const SPEED_065X: Mods = Mods(1 << 25);
+    pub const SPEED_075X: Mods = Mods(1 << 26);
+    pub const SPEED_085X: Mods = Mods(1 << 27);
+    pub const SPEED_095X: Mods = Mods(1 << 28);
+    pub const INVERSE: Mods = Mods(1 << 29);
+    pub const FULL_LN: Mods = Mods(1 << 30);
+    pub const MIRROR: Mods = Mods(1 << 31);
+    pub const COOP: Mods = Mods(1 << 32);
+    pub const SPEED_105X: Mods = Mods(1 << 33);
+    pub const SPEED_115X: Mods = Mods(1 << 34);
+    pub const SPEED_125X: Mods = Mods(1 << 35);
+    pub const SPEED_135X: Mods = Mods(1 << 36);
+    pub const SPEED_145X: Mods = Mods(1 << 37);
+    pub const SPEED_155X: Mods = Mods(1 << 38);
+    pub const SPEED_165X: Mods = Mods(1 << 39);
+    pub const SPEED_175X: Mods = Mods(1 << 40);
+    pub const SPEED_185X: Mods = Mods(1 << 41);
+    pub const SPEED_195X: Mods = Mods(1 << 42);
+    pub const HEALTH_ADJUST: Mods = Mods(1 << 43);
+    pub const NO_MISS: Mods = Mods(1 << 44);
+
+    const NAMED: [(Mods, &'static str); 15] = [
+        (Mods::NO_SLIDER_VELOCITY, "NoSliderVelocity"),
+        (Mods::STRICT, "Strict"),
+        (Mods::CHILL, "Chill"),
+        (Mods::NO_PAUSE, "NoPause"),
+        (Mods::AUTOPLAY, "Autoplay"),
+        (Mods::PAUSED, "Paused"),
+        (Mods::NO_FAIL, "NoFail"),
+        (Mods::NO_LONG_NOTES, "NoLongNotes"),
+        (Mods::RANDOMIZE, "Randomize"),
+        (Mods::INVERSE, "Inverse"),
+        (Mods::FULL_LN, "FullLN"),
+        (Mods::MIRROR, "Mirror"),
+        (Mods::COOP, "Coop"),
+        (Mods::HEALTH_ADJUST, "HealthAdjust"),
+        (Mods::NO_MISS, "NoMiss"),
+    ];
+    const RATES: [(Mods, f32); 30] = [
+        (Mods::SPEED_050X, 0.5),
+        (Mods::SPEED_055X, 0.55),
+        (Mods::SPEED_060X, 0.6),
+        (Mods::SPEED_065X, 0.65),
+        (Mods::SPEED_070X, 0.7),
+        (Mods::SPEED_075X, 0.75),
+        (Mods::SPEED_080X, 0.8),
+        (Mods::SPEED_085X, 0.85),
+        (Mods::SPEED_090X, 0.9),
+        (Mods::SPEED_095X, 0.95),
+        (Mods::SPEED_105X, 1.05),
+        (Mods::SPEED_110X, 1.1),
+        (Mods::SPEED_115X, 1.15),
+        (Mods::SPEED_120X, 1.2),
+        (Mods::SPEED_125X, 1.25),
+        (Mods::SPEED_130X, 1.3),
+        (Mods::SPEED_135X, 1.35),
+        (Mods::SPEED_140X, 1.4),
+        (Mods::SPEED_145X, 1.45),
+        (Mods::SPEED_150X, 1.5),
+        (Mods::SPEED_155X, 1.55),
+        (Mods::SPEED_160X, 1.6),
+        (Mods::SPEED_165X, 1.65),
+        (Mods::SPEED_170X, 1.7),
+        (Mods::SPEED_175X, 1.75),
+        (Mods::SPEED_180X, 1.8),
+        (Mods::SPEED_185X, 1.85),
+        (Mods::SPEED_190X, 1.9),
+        (Mods::SPEED_195X, 1.95),
+        (Mods::SPEED_200X, 2.0),
+    ];
+
+    pub fn bits(self) -> i64 {
+        self.0
+    }
+
+    pub fn contains(self, other: Mods) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The playback rate that these mods result in; `1.0` if no rate mod is active
+    pub fn rate(self) -> f32 {
+        Mods::RATES
+            .iter()
+            .find(|(rate_mod, _)| self.contains(*rate_mod))
+            .map(|(_, rate)| *rate)
+            .unwrap_or(1.)
+    }
+
+    /// These mods with any rate mods removed
+    pub fn without_rate(self) -> Mods {
+        let rate_mask = Mods::RATES
+            .iter()
+            .fold(0, |acc, (rate_mod, _)| acc | rate_mod.0);
+        Mods(self.0 & !rate_mask)
+    }
+
+    /// Names of all active mods other than rate mods, which are exposed through `rate()` instead
+    pub fn names(self) -> Vec<&'static str> {
+        Mods::NAMED
+            .iter()
+            .filter(|(named_mod, _)| self.contains(*named_mod))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+impl BitOr for Mods {
+    type Output = Mods;
+
+    fn bitor(self, rhs: Mods) -> Mods {
+        Mods(self.0 | rhs.0)
+    }
+}
+
+impl ToSql<Int8, Pg> for Mods {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        <i64 as ToSql<Int8, Pg>>::to_sql(&self.0, out)
+    }
+}
+
+impl FromSql<Int8, Pg> for Mods {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        <i64 as FromSql<Int8, Pg>>::from_sql(bytes).map(Mods)
+    }
+}
+
+impl Serialize for Mods {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Mods", 3)?;
+        state.serialize_field("bits", &self.0)?;
+        state.serialize_field("list", &self.names())?;
+        state.serialize_field("rate", &self.rate())?;
+        state.end()
+    }
+}
+
+/// Upstream sends mods as the raw bitmask, except that some payloads use `-1` for no mods
+impl<'de> Deserialize<'de> for Mods {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        i64::deserialize(deserializer).map(|bits| if bits < 0 { Mods::NONE } else { Mods(bits) })
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize, Insertable, Queryable)]
 #[table_name = "maps"]
@@ -22,12 +428,19 @@ pub struct Map {
     pub ranked_status: i16,
 }
 
+impl Map {
+    /// Only scores on ranked maps count towards overall performance rating
+    pub fn is_ranked(&self) -> bool {
+        self.ranked_status == 2
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct APIScore {
     pub id: i64,
     pub time: DateTime<Utc>,
-    pub mode: i16,
-    pub mods: i64,
+    pub mode: GameMode,
+    pub mods: Mods,
     pub mods_string: String,
     pub performance_rating: f32,
     pub personal_best: bool,
@@ -78,15 +491,14 @@ impl APIScore {
     }
 }
 
-#[derive(Queryable, Serialize, Insertable)]
+#[derive(Queryable, QueryableByName, Serialize, Insertable)]
 #[table_name = "scores"]
 pub struct DBScore {
     pub id: i64,
     pub user_id: i64,
     pub time: NaiveDateTime,
-    pub mode: i16,
-    #[serde(skip_serializing)]
-    pub mods: i64,
+    pub mode: GameMode,
+    pub mods: Mods,
     pub mods_string: String,
     pub performance_rating: f32,
     pub personal_best: bool,
@@ -107,23 +519,170 @@ pub struct DBScore {
     pub map_id: i64,
 }
 
+/// A user's best score on a map, or on a map with a particular combination of mods
+#[derive(QueryableByName, Serialize)]
+pub struct TopScore {
+    #[diesel(embed)]
+    #[serde(flatten)]
+    pub score: DBScore,
+    /// Number of stored scores the user has on the map, across all mods
+    #[sql_type = "Int8"]
+    pub play_count: i64,
+}
+
+/// A score in a user's history on a map, annotated with how it compares to their earlier scores
+#[derive(Serialize)]
+pub struct ScoreHistoryEntry {
+    #[serde(flatten)]
+    pub score: DBScore,
+    /// Whether this score beat the performance rating of every earlier score on the map
+    pub new_personal_best: bool,
+    /// How much this score improved on the previous personal best, if it's a new personal best and
+    /// there was one before it
+    pub improvement: Option<ScoreImprovement>,
+}
+
+#[derive(Serialize)]
+pub struct ScoreImprovement {
+    pub previous_best_id: i64,
+    pub performance_rating: f32,
+    pub accuracy: f32,
+    pub previous_grade: String,
+}
+
+impl ScoreHistoryEntry {
+    /// Annotates `scores`, which must all be on the same map and sorted oldest first, with the
+    /// user's personal best progression.  The `personal_best` flag of stored scores only reflects
+    /// what upstream said when they were fetched, so it isn't used.
+    pub fn from_scores(scores: Vec<DBScore>) -> Vec<Self> {
+        let mut best: Option<(i64, f32, f32, String)> = None;
+
+        scores
+            .into_iter()
+            .map(|score| {
+                let new_personal_best = match &best {
+                    Some((_, rating, ..)) => score.performance_rating > *rating,
+                    None => true,
+                };
+                if !new_personal_best {
+                    return ScoreHistoryEntry {
+                        score,
+                        new_personal_best,
+                        improvement: None,
+                    };
+                }
+
+                let improvement =
+                    best.take()
+                        .map(|(previous_best_id, rating, accuracy, previous_grade)| {
+                            ScoreImprovement {
+                                previous_best_id,
+                                performance_rating: score.performance_rating - rating,
+                                accuracy: score.accuracy - accuracy,
+                                previous_grade,
+                            }
+                        });
+                best = Some((
+                    score.id,
+                    score.performance_rating,
+                    score.accuracy,
+                    score.grade.clone(),
+                ));
+
+                ScoreHistoryEntry {
+                    score,
+                    new_personal_best,
+                    improvement,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Upstream has sent timestamps both as RFC 3339 strings and as milliseconds since the epoch, and
+/// sometimes sends values that are neither.  Anything that can't be interpreted as a timestamp is
+/// treated as missing rather than failing the whole response.
+fn deserialize_lenient_timestamp<'de, D: Deserializer<'de>>(
+    deserializer: D,
+    field: &str,
+) -> Result<Option<DateTime<Utc>>, D::Error> {
+    let value = serde_json::Value::deserialize(deserializer)?;
+    let timestamp = match &value {
+        serde_json::Value::Null => return Ok(None),
+        serde_json::Value::String(s) => s.parse::<DateTime<Utc>>().ok(),
+        serde_json::Value::Number(n) => n
+            .as_i64()
+            .and_then(|millis| Utc.timestamp_millis_opt(millis).single()),
+        _ => None,
+    };
+
+    if timestamp.is_none() {
+        warn!(
+            "Ignoring invalid timestamp for `{}` from Quaver API: {}",
+            field, value
+        );
+    }
+    Ok(timestamp)
+}
+
+/// `deserialize_with` functions for the timestamp fields that are decoded leniently, so that
+/// dropped values are logged along with the field they came from
+mod lenient_timestamp {
+    use chrono::{DateTime, Utc};
+    use serde::Deserializer;
+
+    pub fn time_registered<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<DateTime<Utc>>, D::Error> {
+        super::deserialize_lenient_timestamp(deserializer, "time_registered")
+    }
+
+    pub fn mute_endtime<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<DateTime<Utc>>, D::Error> {
+        super::deserialize_lenient_timestamp(deserializer, "mute_endtime")
+    }
+
+    pub fn latest_activity<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<DateTime<Utc>>, D::Error> {
+        super::deserialize_lenient_timestamp(deserializer, "latest_activity")
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct APIStatsUserInfo {
     pub id: i64,
     pub steam_id: Option<String>,
     pub username: String,
+    #[serde(default, deserialize_with = "lenient_timestamp::time_registered")]
     pub time_registered: Option<DateTime<Utc>>,
+    #[serde(default)]
     pub allowed: i64,
-    pub privileges: i64,
-    pub usergroups: i64,
+    /// Missing from some responses, in which case the stored value is kept
+    #[serde(default)]
+    pub privileges: Option<i64>,
+    #[serde(default)]
+    pub usergroups: Option<i64>,
+    #[serde(default, deserialize_with = "lenient_timestamp::mute_endtime")]
     pub mute_endtime: Option<DateTime<Utc>>,
-    pub latest_activity: String,
-    pub country: String,
+    #[serde(default, deserialize_with = "lenient_timestamp::latest_activity")]
+    pub latest_activity: Option<DateTime<Utc>>,
+    pub country: Option<String>,
     pub avatar_url: Option<String>,
+    #[serde(default)]
     pub userpage: ::serde_json::Value,
+    #[serde(default)]
     pub online: bool,
 }
 
+impl APIStatsUserInfo {
+    pub fn latest_activity_at(&self) -> Option<NaiveDateTime> {
+        self.latest_activity
+            .map(|latest_activity| latest_activity.naive_utc())
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 pub enum ActivityMap {
@@ -135,77 +694,168 @@ pub enum ActivityMap {
 pub struct ActivityFeed {
     pub id: i64,
     #[serde(rename = "type")]
-    pub type_field: i64,
+    pub type_field: i16,
     pub timestamp: DateTime<Utc>,
     pub map: Option<ActivityMap>,
 }
 
+impl ActivityFeed {
+    pub fn to_db(self, user_id: i64) -> (Option<Map>, NewDBActivityEvent) {
+        let (map, map_id, map_name) = match self.map {
+            Some(ActivityMap::Map(map)) => {
+                let map_id = map.id;
+                (Some(map), Some(map_id), None)
+            },
+            // Placeholder maps have negative ids
+            Some(ActivityMap::EmptyMap { id, name }) => {
+                (None, Some(id).filter(|&id| id > 0), Some(name))
+            },
+            None => (None, None, None),
+        };
+
+        let event = NewDBActivityEvent {
+            id: self.id,
+            user_id,
+            activity_type: self.type_field,
+            timestamp: self.timestamp.naive_utc(),
+            map_id,
+            map_name,
+        };
+
+        (map, event)
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "activity_events"]
+pub struct NewDBActivityEvent {
+    pub id: i64,
+    pub user_id: i64,
+    pub activity_type: i16,
+    pub timestamp: NaiveDateTime,
+    pub map_id: Option<i64>,
+    pub map_name: Option<String>,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct DBActivityEvent {
+    pub id: i64,
+    #[serde(skip_serializing)]
+    pub user_id: i64,
+    /// The raw upstream activity type.  `0` is registration and `7` is an unlocked achievement,
+    /// in which case `map_name` is the achievement's name and `map_id` is empty.
+    pub activity_type: i16,
+    pub timestamp: NaiveDateTime,
+    pub map_id: Option<i64>,
+    pub map_name: Option<String>,
+}
+
 #[derive(Deserialize, Debug, Clone)]
+pub struct APIProfileBadge {
+    pub id: i64,
+    pub name: String,
+    pub description: Option<String>,
+}
+
+#[derive(Insertable)]
+#[table_name = "user_badges"]
+pub struct NewDBUserBadge {
+    pub user_id: i64,
+    pub badge_id: i64,
+    pub name: String,
+    pub description: Option<String>,
+    pub first_seen_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct DBUserBadge {
+    #[serde(skip_serializing)]
+    pub user_id: i64,
+    pub badge_id: i64,
+    pub name: String,
+    pub description: Option<String>,
+    pub first_seen_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    pub removed_at: Option<NaiveDateTime>,
+}
+
+/// Badges that a user gained or lost since the last time they were updated
+#[derive(Default, Serialize)]
+pub struct BadgeChanges {
+    pub gained: Vec<DBUserBadge>,
+    pub removed: Vec<DBUserBadge>,
+}
+
+#[derive(Debug, Clone)]
 pub struct APIStatsUser {
     pub info: APIStatsUserInfo,
-    pub profile_badges: Vec<::serde_json::Value>,
+    pub profile_badges: Vec<APIProfileBadge>,
     pub activity_feed: Vec<ActivityFeed>,
-    pub keys4: APIModeStats,
-    pub keys7: APIModeStats,
+    /// Stats for every mode that upstream returned, collected from the `keys4`, `keys7`, etc.
+    /// fields of the response
+    pub mode_stats: BTreeMap<GameMode, APIModeStats>,
 }
 
-impl APIStatsUser {
-    pub fn to_db(self) -> [NewDBStatsUpdate; 2] {
-        let user_id = self.info.id;
+/// Each mode's stats are under the key given by [`GameMode::api_key`], so this is written out by
+/// hand to pick those up for every mode.  The values are still decoded one field at a time so that
+/// error paths and unknown field reporting work inside them.
+impl<'de> Deserialize<'de> for APIStatsUser {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct APIStatsUserVisitor;
 
-        let update_4k = NewDBStatsUpdate {
-            user_id,
-            mode: 1,
-            total_score: self.keys4.stats.total_score,
-            ranked_score: self.keys4.stats.ranked_score,
-            overall_accuracy: self.keys4.stats.overall_accuracy,
-            overall_performance_rating: self.keys4.stats.overall_performance_rating,
-            play_count: self.keys4.stats.play_count,
-            fail_count: self.keys4.stats.fail_count,
-            max_combo: self.keys4.stats.max_combo,
-            replays_watched: self.keys4.stats.replays_watched,
-            total_marv: self.keys4.stats.total_marv,
-            total_perf: self.keys4.stats.total_perf,
-            total_great: self.keys4.stats.total_great,
-            total_good: self.keys4.stats.total_good,
-            total_okay: self.keys4.stats.total_okay,
-            total_miss: self.keys4.stats.total_miss,
-            total_pauses: self.keys4.stats.total_pauses,
-            multiplayer_wins: self.keys4.stats.multiplayer_wins,
-            multiplayer_losses: self.keys4.stats.multiplayer_losses,
-            multiplayer_ties: self.keys4.stats.multiplayer_ties,
-            global_rank: self.keys4.global_rank,
-            country_rank: self.keys4.country_rank,
-            multiplayer_win_rank: self.keys4.multiplayer_win_rank,
-        };
+        impl<'de> Visitor<'de> for APIStatsUserVisitor {
+            type Value = APIStatsUser;
 
-        let update_7k = NewDBStatsUpdate {
-            user_id,
-            mode: 2,
-            total_score: self.keys7.stats.total_score,
-            ranked_score: self.keys7.stats.ranked_score,
-            overall_accuracy: self.keys7.stats.overall_accuracy,
-            overall_performance_rating: self.keys7.stats.overall_performance_rating,
-            play_count: self.keys7.stats.play_count,
-            fail_count: self.keys7.stats.fail_count,
-            max_combo: self.keys7.stats.max_combo,
-            replays_watched: self.keys7.stats.replays_watched,
-            total_marv: self.keys7.stats.total_marv,
-            total_perf: self.keys7.stats.total_perf,
-            total_great: self.keys7.stats.total_great,
-            total_good: self.keys7.stats.total_good,
-            total_okay: self.keys7.stats.total_okay,
-            total_miss: self.keys7.stats.total_miss,
-            total_pauses: self.keys7.stats.total_pauses,
-            multiplayer_wins: self.keys7.stats.multiplayer_wins,
-            multiplayer_losses: self.keys7.stats.multiplayer_losses,
-            multiplayer_ties: self.keys7.stats.multiplayer_ties,
-            global_rank: self.keys7.global_rank,
-            country_rank: self.keys7.country_rank,
-            multiplayer_win_rank: self.keys7.multiplayer_win_rank,
-        };
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a Quaver user with stats")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<APIStatsUser, A::Error> {
+                let mut info = None;
+                let mut profile_badges = None;
+                let mut activity_feed = None;
+                let mut mode_stats = BTreeMap::new();
+
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "info" => info = Some(map.next_value()?),
+                        "profile_badges" => profile_badges = Some(map.next_value()?),
+                        "activity_feed" => activity_feed = Some(map.next_value()?),
+                        _ => match GameMode::from_api_key(&key) {
+                            Some(mode) => {
+                                mode_stats.insert(mode, map.next_value()?);
+                            },
+                            None => {
+                                map.next_value::<IgnoredAny>()?;
+                            },
+                        },
+                    }
+                }
+
+                Ok(APIStatsUser {
+                    info: info.ok_or_else(|| A::Error::missing_field("info"))?,
+                    profile_badges: profile_badges
+                        .ok_or_else(|| A::Error::missing_field("profile_badges"))?,
+                    activity_feed: activity_feed
+                        .ok_or_else(|| A::Error::missing_field("activity_feed"))?,
+                    mode_stats,
+                })
+            }
+        }
+
+        deserializer.deserialize_map(APIStatsUserVisitor)
+    }
+}
+
+impl APIStatsUser {
+    pub fn to_db(self) -> Vec<NewDBStatsUpdate> {
+        let user_id = self.info.id;
 
-        [update_4k, update_7k]
+        self.mode_stats
+            .into_iter()
+            .map(|(mode, stats)| stats.to_db(user_id, mode))
+            .collect()
     }
 }
 
@@ -247,6 +897,36 @@ pub struct APIModeStats {
     pub stats: APIStats,
 }
 
+impl APIModeStats {
+    pub fn to_db(self, user_id: i64, mode: GameMode) -> NewDBStatsUpdate {
+        NewDBStatsUpdate {
+            user_id,
+            mode,
+            total_score: self.stats.total_score,
+            ranked_score: self.stats.ranked_score,
+            overall_accuracy: self.stats.overall_accuracy,
+            overall_performance_rating: self.stats.overall_performance_rating,
+            play_count: self.stats.play_count,
+            fail_count: self.stats.fail_count,
+            max_combo: self.stats.max_combo,
+            replays_watched: self.stats.replays_watched,
+            total_marv: self.stats.total_marv,
+            total_perf: self.stats.total_perf,
+            total_great: self.stats.total_great,
+            total_good: self.stats.total_good,
+            total_okay: self.stats.total_okay,
+            total_miss: self.stats.total_miss,
+            total_pauses: self.stats.total_pauses,
+            multiplayer_wins: self.stats.multiplayer_wins,
+            multiplayer_losses: self.stats.multiplayer_losses,
+            multiplayer_ties: self.stats.multiplayer_ties,
+            global_rank: self.global_rank,
+            country_rank: self.country_rank,
+            multiplayer_win_rank: self.multiplayer_win_rank,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct APIScoresResponse {
     pub status: u32,
@@ -257,7 +937,7 @@ pub struct APIScoresResponse {
 #[table_name = "stats_updates"]
 pub struct NewDBStatsUpdate {
     pub user_id: i64,
-    pub mode: i16,
+    pub mode: GameMode,
     pub total_score: i64,
     pub ranked_score: i64,
     pub overall_accuracy: f32,
@@ -281,14 +961,46 @@ pub struct NewDBStatsUpdate {
     pub multiplayer_win_rank: i64,
 }
 
-#[derive(Queryable, Serialize)]
+impl NewDBStatsUpdate {
+    /// Returns `true` if every stat in this snapshot is the same as in `existing`, meaning that
+    /// there's no need to store it again.  Ranks are compared too so that rank history is kept
+    /// while the player is idle.
+    pub fn matches(&self, existing: &DBStatsUpdate) -> bool {
+        self.user_id == existing.user_id
+            && self.mode == existing.mode
+            && self.total_score == existing.total_score
+            && self.ranked_score == existing.ranked_score
+            && self.overall_accuracy == existing.overall_accuracy
+            && self.overall_performance_rating == existing.overall_performance_rating
+            && self.play_count == existing.play_count
+            && self.fail_count == existing.fail_count
+            && self.max_combo == existing.max_combo
+            && self.replays_watched == existing.replays_watched
+            && self.total_marv == existing.total_marv
+            && self.total_perf == existing.total_perf
+            && self.total_great == existing.total_great
+            && self.total_good == existing.total_good
+            && self.total_okay == existing.total_okay
+            && self.total_miss == existing.total_miss
+            && self.total_pauses == existing.total_pauses
+            && self.multiplayer_wins == existing.multiplayer_wins
+            && self.multiplayer_losses == existing.multiplayer_losses
+            && self.multiplayer_ties == existing.multiplayer_ties
+            && self.country_rank == existing.country_rank
+            && self.global_rank == existing.global_rank
+            && self.multiplayer_win_rank == existing.multiplayer_win_rank
+    }
+}
+
+#[derive(Queryable, QueryableByName, Serialize)]
+#[table_name = "stats_updates"]
 pub struct DBStatsUpdate {
     #[serde(skip_serializing)]
     pub id: i32,
     #[serde(skip_serializing)]
     pub user_id: i64,
     pub recorded_at: NaiveDateTime,
-    pub mode: i16,
+    pub mode: GameMode,
     pub total_score: i64,
     pub ranked_score: i64,
     pub overall_accuracy: f32,
@@ -310,6 +1022,8 @@ pub struct DBStatsUpdate {
     pub country_rank: i64,
     pub global_rank: i64,
     pub multiplayer_win_rank: i64,
+    /// Last time that upstream returned stats identical to this snapshot
+    pub last_checked_at: NaiveDateTime,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -318,12 +1032,19 @@ pub struct APIUser {
     pub steam_id: Option<String>,
     pub username: String,
     pub country: Option<String>,
+    #[serde(default, deserialize_with = "lenient_timestamp::time_registered")]
     pub time_registered: Option<DateTime<Utc>>,
+    #[serde(default)]
     pub allowed: bool,
-    pub privileges: i64,
-    pub usergroups: i64,
+    /// Missing from some responses, in which case the stored value is kept
+    #[serde(default)]
+    pub privileges: Option<i64>,
+    #[serde(default)]
+    pub usergroups: Option<i64>,
+    #[serde(default, deserialize_with = "lenient_timestamp::mute_endtime")]
     pub mute_endtime: Option<DateTime<Utc>>,
-    pub latest_activity: String,
+    #[serde(default, deserialize_with = "lenient_timestamp::latest_activity")]
+    pub latest_activity: Option<DateTime<Utc>>,
     pub avatar_url: Option<String>,
 }
 
@@ -347,7 +1068,28 @@ pub struct APISearchUsersResponse {
     pub users: Vec<APISearchUser>,
 }
 
-#[derive(Insertable)]
+#[derive(Queryable, Serialize, Clone, Debug)]
+pub struct DBUser {
+    pub id: i64,
+    pub username: String,
+    pub steam_id: Option<String>,
+    pub time_registered: Option<NaiveDateTime>,
+    pub country: String,
+    pub avatar_url: String,
+    pub last_updated_at: Option<NaiveDateTime>,
+    pub next_update_at: Option<NaiveDateTime>,
+    pub privileges: Option<i64>,
+    pub usergroups: Option<i64>,
+    pub mute_endtime: Option<NaiveDateTime>,
+    pub latest_activity: Option<NaiveDateTime>,
+    pub online: Option<bool>,
+    pub userpage: Option<String>,
+}
+
+/// A user's profile as returned by upstream.  When used to update an existing user, fields that
+/// are `None` are left as they are since the different endpoints users are fetched from don't all
+/// return every field.
+#[derive(Insertable, AsChangeset)]
 #[table_name = "users"]
 pub struct NewDBUser {
     pub id: i64,
@@ -356,17 +1098,237 @@ pub struct NewDBUser {
     pub time_registered: Option<NaiveDateTime>,
     pub country: Option<String>,
     pub avatar_url: Option<String>,
+    pub privileges: Option<i64>,
+    pub usergroups: Option<i64>,
+    pub mute_endtime: Option<NaiveDateTime>,
+    pub latest_activity: Option<NaiveDateTime>,
+    pub online: Option<bool>,
+    pub userpage: Option<String>,
 }
 
 impl From<APIUser> for NewDBUser {
     fn from(other: APIUser) -> Self {
         NewDBUser {
             id: other.id,
-            username: other.username,
+            username: other.username.to_lowercase(),
             steam_id: other.steam_id,
             time_registered: other.time_registered.map(|dt| dt.naive_utc()),
             country: other.country,
             avatar_url: other.avatar_url,
+            privileges: other.privileges,
+            usergroups: other.usergroups,
+            mute_endtime: other.mute_endtime.map(|dt| dt.naive_utc()),
+            latest_activity: other.latest_activity.map(|dt| dt.naive_utc()),
+            online: None,
+            userpage: None,
         }
     }
 }
+
+impl From<APIStatsUserInfo> for NewDBUser {
+    fn from(other: APIStatsUserInfo) -> Self {
+        let userpage = match other.userpage {
+            serde_json::Value::Null => None,
+            serde_json::Value::String(userpage) => Some(userpage),
+            userpage => Some(userpage.to_string()),
+        };
+
+        NewDBUser {
+            id: other.id,
+            username: other.username.to_lowercase(),
+            steam_id: other.steam_id,
+            time_registered: other.time_registered.map(|dt| dt.naive_utc()),
+            country: other.country,
+            avatar_url: other.avatar_url,
+            privileges: other.privileges,
+            usergroups: other.usergroups,
+            mute_endtime: other.mute_endtime.map(|dt| dt.naive_utc()),
+            latest_activity: other.latest_activity.map(|dt| dt.naive_utc()),
+            online: Some(other.online),
+            userpage,
+        }
+    }
+}
+
+/// A user's username, country, avatar and usergroups as of `recorded_at`.  A new entry is only
+/// recorded when one of them changes.
+#[derive(Queryable, Serialize, Clone, Debug)]
+pub struct DBUserProfile {
+    #[serde(skip_serializing)]
+    pub id: i32,
+    #[serde(skip_serializing)]
+    pub user_id: i64,
+    pub username: String,
+    pub country: String,
+    pub avatar_url: String,
+    pub usergroups: Option<i64>,
+    pub recorded_at: NaiveDateTime,
+}
+
+/// A user that a lookup might have been referring to
+#[derive(QueryableByName, Serialize, Clone, Debug, PartialEq)]
+pub struct UserCandidate {
+    #[sql_type = "Int8"]
+    pub id: i64,
+    #[sql_type = "Text"]
+    pub username: String,
+    /// The current or previous username of this user that matched the lookup
+    #[sql_type = "Text"]
+    pub matched_username: String,
+    /// Trigram similarity between the lookup and `matched_username` from 0 to 1, if this
+    /// candidate came from a fuzzy search
+    #[sql_type = "Nullable<Float4>"]
+    pub similarity: Option<f32>,
+}
+
+/// A tracked user matching a search, with enough of their profile to show as a suggestion
+#[derive(QueryableByName, Serialize, Clone, Debug)]
+pub struct UserSearchResult {
+    #[sql_type = "Int8"]
+    pub id: i64,
+    #[sql_type = "Text"]
+    pub username: String,
+    /// The current or previous username of this user that matched the search
+    #[sql_type = "Text"]
+    pub matched_username: String,
+    #[sql_type = "Text"]
+    pub avatar_url: String,
+    #[sql_type = "Text"]
+    pub country: String,
+    #[sql_type = "Nullable<Int8>"]
+    pub global_rank_4k: Option<i64>,
+    #[sql_type = "Nullable<Int8>"]
+    pub global_rank_7k: Option<i64>,
+    #[sql_type = "Nullable<Timestamp>"]
+    pub last_updated_at: Option<NaiveDateTime>,
+    /// Higher is better.  Combines how well the search matched with how active the user is.
+    #[sql_type = "Float8"]
+    pub rank: f64,
+}
+
+impl From<APISearchUser> for UserCandidate {
+    fn from(other: APISearchUser) -> Self {
+        let username = other.username.to_lowercase();
+        UserCandidate {
+            id: other.id,
+            matched_username: username.clone(),
+            username,
+            similarity: None,
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "user_profile_history"]
+pub struct NewDBUserProfile {
+    pub user_id: i64,
+    pub username: String,
+    pub country: String,
+    pub avatar_url: String,
+    pub usergroups: Option<i64>,
+    pub recorded_at: NaiveDateTime,
+}
+
+impl NewDBUserProfile {
+    pub fn new(user: &DBUser, recorded_at: NaiveDateTime) -> Self {
+        NewDBUserProfile {
+            user_id: user.id,
+            username: user.username.clone(),
+            country: user.country.clone(),
+            avatar_url: user.avatar_url.clone(),
+            usergroups: user.usergroups,
+            recorded_at,
+        }
+    }
+
+    /// Returns `true` if this profile is the same as `existing`, ignoring when each was recorded
+    pub fn matches(&self, existing: &DBUserProfile) -> bool {
+        self.username == existing.username
+            && self.country == existing.country
+            && self.avatar_url == existing.avatar_url
+            && self.usergroups == existing.usergroups
+    }
+}
+
+#[test]
+fn mods_decoding() {
+    let mods = Mods::MIRROR | Mods::NO_LONG_NOTES | Mods::SPEED_120X;
+    assert_eq!(mods.names(), vec!["NoLongNotes", "Mirror"]);
+    assert!((mods.rate() - 1.2).abs() < f32::EPSILON);
+    assert_eq!(mods.without_rate(), Mods::MIRROR | Mods::NO_LONG_NOTES);
+
+    assert!(Mods::NONE.names().is_empty());
+    assert!((Mods::NONE.rate() - 1.).abs() < f32::EPSILON);
+    assert!((Mods::SPEED_050X.rate() - 0.5).abs() < f32::EPSILON);
+    assert!((Mods(1 << 15).rate() - 2.).abs() < f32::EPSILON);
+
+    assert_eq!(serde_json::from_str::<Mods>("-1").unwrap(), Mods::NONE);
+    assert_eq!(
+        serde_json::from_str::<Mods>("128").unwrap(),
+        Mods::SPEED_120X
+    );
+
+    let serialized = serde_json::to_value(Mods::AUTOPLAY | Mods::SPEED_095X).unwrap();
+    assert_eq!(
+        serialized,
+        serde_json::json!({ "bits": (1i64 << 19) | (1 << 28), "list": ["Autoplay"], "rate": 0.95f32 })
+    );
+}
+
+#[test]
+fn game_mode_parsing() {
+    for raw in &["1", "4", "4k", "4K", "k4", "keys4"] {
+        assert_eq!(raw.parse::<GameMode>().unwrap(), GameMode::Keys4);
+    }
+    for raw in &["2", "7", "7k", "k7", "Keys7"] {
+        assert_eq!(raw.parse::<GameMode>().unwrap(), GameMode::Keys7);
+    }
+    assert!("5k".parse::<GameMode>().is_err());
+    assert!(GameMode::try_from(3).is_err());
+
+    assert_eq!(serde_json::to_string(&GameMode::Keys7).unwrap(), "2");
+    assert_eq!(
+        serde_json::from_str::<GameMode>("1").unwrap(),
+        GameMode::Keys4
+    );
+}
+
+#[test]
+fn lenient_user_deserialization() {
+    let user: APIUser = serde_json::from_str(
+        r#"{"id":1,"steam_id":null,"username":"a","country":"US","time_registered":{"date":"?"},"mute_endtime":null,"latest_activity":1596923439855,"avatar_url":null}"#,
+    )
+    .unwrap();
+
+    assert_eq!(user.time_registered, None);
+    assert_eq!(user.mute_endtime, None);
+    assert_eq!(
+        user.latest_activity.unwrap().to_rfc3339(),
+        "2020-08-08T21:50:39.855+00:00"
+    );
+    assert!(!user.allowed);
+}
+
+#[test]
+fn stats_history_cursor_round_trip() {
+    let cursor = StatsHistoryCursor {
+        recorded_at: NaiveDateTime::parse_from_str(
+            "1969-12-31 23:59:59.123456",
+            "%Y-%m-%d %H:%M:%S%.f",
+        )
+        .unwrap(),
+        id: 42,
+    };
+    assert_eq!(
+        cursor.to_string().parse::<StatsHistoryCursor>().unwrap(),
+        cursor
+    );
+
+    for invalid in &["", "42", "abc_1", "1_abc", "1_2_3"] {
+        assert!(
+            invalid.parse::<StatsHistoryCursor>().is_err(),
+            "{}",
+            invalid
+        );
+    }
+}
@@ -1,3 +1,14 @@
+table! {
+    activity_events (id) {
+        id -> Int8,
+        user_id -> Int8,
+        activity_type -> Int2,
+        timestamp -> Timestamp,
+        map_id -> Nullable<Int8>,
+        map_name -> Nullable<Text>,
+    }
+}
+
 table! {
     maps (id) {
         id -> Int8,
@@ -39,6 +50,37 @@ table! {
     }
 }
 
+table! {
+    stats_rollups (id) {
+        id -> Int4,
+        user_id -> Int8,
+        recorded_at -> Timestamp,
+        mode -> Int2,
+        total_score -> Int8,
+        ranked_score -> Int8,
+        overall_accuracy -> Float4,
+        overall_performance_rating -> Float4,
+        play_count -> Int8,
+        fail_count -> Int8,
+        max_combo -> Int8,
+        replays_watched -> Int8,
+        total_marv -> Int8,
+        total_perf -> Int8,
+        total_great -> Int8,
+        total_good -> Int8,
+        total_okay -> Int8,
+        total_miss -> Int8,
+        total_pauses -> Int8,
+        multiplayer_wins -> Int8,
+        multiplayer_losses -> Int8,
+        multiplayer_ties -> Int8,
+        country_rank -> Int8,
+        global_rank -> Int8,
+        multiplayer_win_rank -> Int8,
+        last_checked_at -> Timestamp,
+    }
+}
+
 table! {
     stats_updates (id) {
         id -> Int4,
@@ -66,6 +108,31 @@ table! {
         country_rank -> Int8,
         global_rank -> Int8,
         multiplayer_win_rank -> Int8,
+        last_checked_at -> Timestamp,
+    }
+}
+
+table! {
+    user_badges (user_id, badge_id) {
+        user_id -> Int8,
+        badge_id -> Int8,
+        name -> Text,
+        description -> Nullable<Text>,
+        first_seen_at -> Timestamp,
+        last_seen_at -> Timestamp,
+        removed_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    user_profile_history (id) {
+        id -> Int4,
+        user_id -> Int8,
+        username -> Text,
+        country -> Varchar,
+        avatar_url -> Text,
+        usergroups -> Nullable<Int8>,
+        recorded_at -> Timestamp,
     }
 }
 
@@ -78,14 +145,26 @@ table! {
         country -> Varchar,
         avatar_url -> Text,
         last_updated_at -> Nullable<Timestamp>,
+        next_update_at -> Nullable<Timestamp>,
+        privileges -> Nullable<Int8>,
+        usergroups -> Nullable<Int8>,
+        mute_endtime -> Nullable<Timestamp>,
+        latest_activity -> Nullable<Timestamp>,
+        online -> Nullable<Bool>,
+        userpage -> Nullable<Text>,
     }
 }
 
+joinable!(activity_events -> maps (map_id));
 joinable!(scores -> maps (map_id));
 
 allow_tables_to_appear_in_same_query!(
+    activity_events,
     maps,
     scores,
+    stats_rollups,
     stats_updates,
+    user_badges,
+    user_profile_history,
     users,
 );
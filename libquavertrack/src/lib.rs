@@ -6,3 +6,6 @@ extern crate log;
 
 pub mod api;
 pub mod db_util;
+pub mod rating;
+pub mod retention;
+pub mod scheduling;
@@ -0,0 +1,165 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+
+use crate::db_util::models::{DBScore, Map};
+
+/// Each score counts for this much less than the one rated just above it
+pub const SCORE_WEIGHT_DECAY: f64 = 0.95;
+/// How far above upstream's overall rating a locally computed one can be before it's considered
+/// inconsistent, relative to upstream's
+pub const RATING_TOLERANCE: f64 = 0.01;
+
+/// Overall performance rating for a set of score ratings, in any order.  Like upstream, this is a
+/// weighted sum where the `i`th best score is weighted by `SCORE_WEIGHT_DECAY^i`.
+pub fn overall_rating(mut ratings: Vec<f32>) -> f64 {
+    ratings.sort_unstable_by(|a, b| b.total_cmp(a));
+
+    ratings
+        .into_iter()
+        .enumerate()
+        .map(|(i, rating)| rating as f64 * SCORE_WEIGHT_DECAY.powi(i as i32))
+        .sum()
+}
+
+/// The scores that count towards a user's overall rating: their highest rated passing score on
+/// each ranked map
+pub struct RatedScores {
+    best_by_map: HashMap<i64, f32>,
+}
+
+impl RatedScores {
+    /// Scores on maps that aren't in `maps` are assumed to be ranked.
+    pub fn new(scores: &[DBScore], maps: &[Map]) -> Self {
+        let unranked_map_ids: HashSet<i64> = maps
+            .iter()
+            .filter(|map| !map.is_ranked())
+            .map(|map| map.id)
+            .collect();
+
+        let mut best_by_map: HashMap<i64, f32> = HashMap::new();
+        for score in scores {
+            if score.grade == "F" || unranked_map_ids.contains(&score.map_id) {
+                continue;
+            }
+
+            let best = best_by_map.entry(score.map_id).or_insert(0.);
+            *best = best.max(score.performance_rating);
+        }
+
+        RatedScores { best_by_map }
+    }
+
+    pub fn len(&self) -> usize {
+        self.best_by_map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.best_by_map.is_empty()
+    }
+
+    pub fn overall_rating(&self) -> f64 {
+        overall_rating(self.best_by_map.values().copied().collect())
+    }
+
+    /// Overall rating if the user set a new passing score rated `rating`.  If `map_id` is given
+    /// and the user already has a better score on that map, nothing changes.  Otherwise, the new
+    /// score is treated as being on a map they haven't played.
+    pub fn overall_rating_with(&self, map_id: Option<i64>, rating: f32) -> f64 {
+        let mut best_by_map = self.best_by_map.clone();
+        let mut ratings: Vec<f32> = match map_id {
+            Some(map_id) => {
+                let best = best_by_map.entry(map_id).or_insert(0.);
+                *best = best.max(rating);
+                Vec::new()
+            },
+            None => vec![rating],
+        };
+        ratings.extend(best_by_map.values());
+
+        overall_rating(ratings)
+    }
+
+    /// Compares the rating computed from these scores to the one that upstream reported
+    pub fn check(&self, upstream: f32) -> RatingCheck {
+        RatingCheck {
+            computed: self.overall_rating(),
+            upstream: upstream as f64,
+            scores_used: self.len(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RatingCheck {
+    pub computed: f64,
+    pub upstream: f64,
+    pub scores_used: usize,
+}
+
+impl RatingCheck {
+    /// We only have the scores that upstream has returned to us, which often leaves out the tail
+    /// of a user's top scores, so the computed rating is only a lower bound.  Exceeding upstream's
+    /// rating means that we're weighting or selecting scores differently than it does.
+    pub fn is_consistent(&self) -> bool {
+        self.computed <= self.upstream * (1. + RATING_TOLERANCE) + f64::EPSILON
+    }
+}
+
+#[test]
+fn overall_rating_weighting() {
+    let score = |id: i64, map_id: i64, performance_rating: f32, grade: &str| DBScore {
+        id,
+        user_id: 1,
+        time: chrono::NaiveDateTime::parse_from_str("2021-04-13 12:00:00", "%Y-%m-%d %H:%M:%S")
+            .unwrap(),
+        mode: crate::db_util::models::GameMode::Keys4,
+        mods: crate::db_util::models::Mods::NONE,
+        mods_string: "None".to_owned(),
+        performance_rating,
+        personal_best: true,
+        is_donator_score: None,
+        total_score: 900_000,
+        accuracy: 95.,
+        grade: grade.to_owned(),
+        max_combo: 100,
+        count_marv: 0,
+        count_perf: 0,
+        count_great: 0,
+        count_good: 0,
+        count_okay: 0,
+        count_miss: 0,
+        scroll_speed: 20,
+        ratio: 1.,
+        map_id,
+    };
+    let unranked_map: Map = serde_json::from_value(serde_json::json!({
+        "id": 4, "mapset_id": 4, "md5": "", "artist": "", "title": "", "difficulty_name": "",
+        "creator_id": 1, "creator_username": "", "ranked_status": 1,
+    }))
+    .unwrap();
+
+    let scores = RatedScores::new(
+        &[
+            score(1, 1, 10., "A"),
+            // Only the best score on each map counts
+            score(2, 1, 9., "A"),
+            score(3, 2, 20., "S"),
+            // Failed scores and scores on unranked maps don't count
+            score(4, 3, 30., "F"),
+            score(5, 4, 30., "A"),
+        ],
+        &[unranked_map],
+    );
+    assert_eq!(scores.len(), 2);
+    assert!((scores.overall_rating() - (20. + 10. * 0.95)).abs() < 1e-9);
+
+    // Beating the best score on a map replaces it, and new maps push the others down
+    assert!((scores.overall_rating_with(Some(1), 5.) - scores.overall_rating()).abs() < 1e-9);
+    assert!((scores.overall_rating_with(Some(1), 15.) - (20. + 15. * 0.95)).abs() < 1e-9);
+    let expected = 25. + 20. * 0.95 + 10. * 0.95 * 0.95;
+    assert!((scores.overall_rating_with(None, 25.) - expected).abs() < 1e-9);
+
+    assert!(scores.check(40.).is_consistent());
+    assert!(!scores.check(20.).is_consistent());
+}
@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use chrono::{Datelike, NaiveDateTime};
+
+/// Decides how long stats snapshots are kept at full resolution.  Snapshots older than
+/// `raw_retention` are rolled up into daily buckets and ones older than `daily_retention` into
+/// weekly buckets.  Rolling up keeps the first and last snapshot of every bucket, and a user's
+/// latest snapshot in each mode is never rolled up.
+#[derive(Clone, Debug)]
+pub struct RetentionPolicy {
+    pub raw_retention: Duration,
+    pub daily_retention: Duration,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            raw_retention: Duration::from_secs(90 * 24 * 60 * 60),
+            daily_retention: Duration::from_secs(365 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// Start of the day that `timestamp` falls in, matching Postgres' `date_trunc('day', ...)`
+fn start_of_day(timestamp: NaiveDateTime) -> NaiveDateTime {
+    timestamp.date().and_hms_opt(0, 0, 0).unwrap()
+}
+
+/// Start of the week (Monday) that `timestamp` falls in, matching Postgres'
+/// `date_trunc('week', ...)`
+fn start_of_week(timestamp: NaiveDateTime) -> NaiveDateTime {
+    start_of_day(timestamp)
+        - chrono::Duration::days(timestamp.weekday().num_days_from_monday() as i64)
+}
+
+fn before(now: NaiveDateTime, duration: Duration) -> NaiveDateTime {
+    now - chrono::Duration::from_std(duration).unwrap_or_else(|_| chrono::Duration::weeks(52))
+}
+
+impl RetentionPolicy {
+    /// Returns the times before which snapshots are kept at daily and weekly resolution.  Both are
+    /// aligned to the start of a bucket so that buckets are never split between resolutions.
+    pub fn cutoffs(&self, now: NaiveDateTime) -> (NaiveDateTime, NaiveDateTime) {
+        let daily_cutoff = start_of_day(before(now, self.raw_retention));
+        let weekly_cutoff = start_of_week(before(now, self.daily_retention).min(daily_cutoff));
+        (daily_cutoff, weekly_cutoff)
+    }
+}
+
+#[test]
+fn cutoffs_are_aligned_to_buckets() {
+    let policy = RetentionPolicy {
+        raw_retention: Duration::from_secs(24 * 60 * 60),
+        daily_retention: Duration::from_secs(7 * 24 * 60 * 60),
+    };
+    // A Tuesday
+    let now = NaiveDateTime::parse_from_str("2021-04-13 12:34:56", "%Y-%m-%d %H:%M:%S").unwrap();
+
+    let (daily_cutoff, weekly_cutoff) = policy.cutoffs(now);
+    assert_eq!(daily_cutoff.to_string(), "2021-04-12 00:00:00");
+    assert_eq!(weekly_cutoff.to_string(), "2021-04-05 00:00:00");
+
+    // Weekly buckets never extend past the daily cutoff
+    let policy = RetentionPolicy {
+        raw_retention: policy.daily_retention,
+        daily_retention: policy.raw_retention,
+    };
+    let (daily_cutoff, weekly_cutoff) = policy.cutoffs(now);
+    assert_eq!(daily_cutoff.to_string(), "2021-04-06 00:00:00");
+    assert_eq!(weekly_cutoff.to_string(), "2021-04-05 00:00:00");
+}
@@ -0,0 +1,136 @@
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+
+/// What we know about how active a user has been recently
+#[derive(Clone, Debug, Default)]
+pub struct ActivitySignals {
+    /// Number of scores the user has set within the policy's `activity_window`
+    pub recent_score_count: i64,
+    /// When the user's play count or scores last changed in any mode.  Rank changes don't count
+    /// since ranks drift without the user doing anything.
+    pub last_stats_change: Option<NaiveDateTime>,
+    /// Upstream's `latest_activity` for the user
+    pub latest_activity: Option<NaiveDateTime>,
+}
+
+/// Decides how often users are refreshed.  Every user gets a priority between 0 (dormant) and 1
+/// (very active) based on their recent activity, which is mapped onto a refresh interval between
+/// `min_interval` and `max_interval`.
+#[derive(Clone, Debug)]
+pub struct RefreshPolicy {
+    /// How often the most active users are refreshed
+    pub min_interval: Duration,
+    /// How often users that haven't played in a long time are refreshed
+    pub max_interval: Duration,
+    /// How far back to look when counting recent scores
+    pub activity_window: Duration,
+    /// Number of recent scores at which score volume alone gives a priority of 0.5
+    pub score_volume_scale: f64,
+    /// The priority that comes from a user's last activity halves every time this much time
+    /// passes without them doing anything
+    pub activity_half_life: Duration,
+}
+
+impl Default for RefreshPolicy {
+    fn default() -> Self {
+        RefreshPolicy {
+            min_interval: Duration::from_secs(30 * 60),
+            max_interval: Duration::from_secs(7 * 24 * 60 * 60),
+            activity_window: Duration::from_secs(7 * 24 * 60 * 60),
+            score_volume_scale: 20.,
+            activity_half_life: Duration::from_secs(3 * 24 * 60 * 60),
+        }
+    }
+}
+
+impl RefreshPolicy {
+    /// Returns a priority between 0 and 1 for a user with the given activity.  Both how recently
+    /// the user was active and how many scores they've set recently raise the priority; having
+    /// either one high is enough to be considered active.
+    pub fn priority(&self, signals: &ActivitySignals, now: NaiveDateTime) -> f64 {
+        let recency = match signals.last_stats_change.max(signals.latest_activity) {
+            Some(last_active) => {
+                let idle_secs = (now - last_active).num_seconds().max(0) as f64;
+                let half_life_secs = self.activity_half_life.as_secs_f64().max(1.);
+                0.5f64.powf(idle_secs / half_life_secs)
+            },
+            None => 0.,
+        };
+
+        let score_count = signals.recent_score_count.max(0) as f64;
+        let volume = score_count / (score_count + self.score_volume_scale.max(f64::EPSILON));
+
+        1. - (1. - recency) * (1. - volume)
+    }
+
+    /// Maps a priority onto a refresh interval.  The interval shrinks geometrically from
+    /// `max_interval` at priority 0 to `min_interval` at priority 1, so that moderately active
+    /// users still get refreshed much more often than dormant ones.
+    pub fn refresh_interval(&self, priority: f64) -> Duration {
+        let priority = priority.clamp(0., 1.);
+        let min_secs = self.min_interval.as_secs_f64().max(1.);
+        let max_secs = self.max_interval.as_secs_f64().max(min_secs);
+
+        Duration::from_secs_f64(min_secs * (max_secs / min_secs).powf(1. - priority))
+    }
+
+    /// Returns when a user that was just refreshed at `now` should next be refreshed
+    pub fn next_update_at(&self, signals: &ActivitySignals, now: NaiveDateTime) -> NaiveDateTime {
+        let interval = self.refresh_interval(self.priority(signals, now));
+        now + chrono::Duration::from_std(interval).unwrap_or_else(|_| chrono::Duration::weeks(1))
+    }
+}
+
+#[test]
+fn refresh_interval_bounds() {
+    let policy = RefreshPolicy::default();
+
+    assert_eq!(policy.refresh_interval(0.), policy.max_interval);
+    assert_eq!(policy.refresh_interval(1.), policy.min_interval);
+    assert_eq!(policy.refresh_interval(-5.), policy.max_interval);
+    assert_eq!(policy.refresh_interval(5.), policy.min_interval);
+
+    let halfway = policy.refresh_interval(0.5);
+    assert!(halfway > policy.min_interval && halfway < policy.max_interval);
+}
+
+#[test]
+fn active_users_are_refreshed_more_often() {
+    let policy = RefreshPolicy::default();
+    let now = NaiveDateTime::parse_from_str("2021-04-13 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+
+    let never_seen = ActivitySignals::default();
+    assert_eq!(policy.priority(&never_seen, now), 0.);
+    assert_eq!(
+        policy.next_update_at(&never_seen, now),
+        now + chrono::Duration::from_std(policy.max_interval).unwrap()
+    );
+
+    let quit_long_ago = ActivitySignals {
+        recent_score_count: 0,
+        last_stats_change: Some(now - chrono::Duration::days(2 * 365)),
+        latest_activity: Some(now - chrono::Duration::days(2 * 365)),
+    };
+    let played_last_week = ActivitySignals {
+        recent_score_count: 3,
+        last_stats_change: Some(now - chrono::Duration::days(6)),
+        latest_activity: Some(now - chrono::Duration::days(5)),
+    };
+    let grinding = ActivitySignals {
+        recent_score_count: 200,
+        last_stats_change: Some(now - chrono::Duration::minutes(20)),
+        latest_activity: Some(now - chrono::Duration::minutes(5)),
+    };
+
+    let dormant_priority = policy.priority(&quit_long_ago, now);
+    let casual_priority = policy.priority(&played_last_week, now);
+    let active_priority = policy.priority(&grinding, now);
+    assert!(dormant_priority < 0.01);
+    assert!(dormant_priority < casual_priority);
+    assert!(casual_priority < active_priority);
+    assert!(active_priority > 0.99);
+
+    assert!(policy.refresh_interval(dormant_priority) > Duration::from_secs(6 * 24 * 60 * 60));
+    assert!(policy.refresh_interval(active_priority) < Duration::from_secs(35 * 60));
+}